@@ -1,17 +1,32 @@
+mod player_windows;
+mod stats;
+
 use crystalorb_bevy_networking_turbulence::{
-    bevy_networking_turbulence::{
-        MessageChannelMode, MessageChannelSettings, NetworkResource, ReliableChannelSettings,
-    },
+    bevy_networking_turbulence::{ConnectionHandle, NetworkResource, NetworkingPlugin},
     crystalorb::client::{stage::Stage as ClientStage, stage::StageMut as ClientStageMut, Client},
-    CommandChannelSettings, CrystalOrbClientPlugin, WrappedNetworkResource,
 };
 use orbgame_shared::{
+    arena::{Arena, ArenaId, PickupKind, Shape, ZoneKind},
     bevy,
     bevy::prelude::*,
+    bindings::InputDevices,
+    config::Settings,
     crystalorb_bevy_networking_turbulence::{self, bevy_networking_turbulence, crystalorb},
-    game::{GameCommand, GameWorld, PlayerCommand, PlayerId, PlayerInput},
+    game::{
+        codec::ReceivedSnapshots, events::CollisionEvent, pickups::PICKUP_RADIUS, ClientHandle,
+        ClientSlot, GameCommand, GameDisplayState, GameWorld, LocalPlayer, PlayerId, PlayerInput,
+        MAX_LOCAL_PLAYERS,
+    },
+    match_state::MatchPhase,
+    network::{
+        connection::GameNetwork, Fingerprint, HandshakeMessage, NetworkSettingsPlugin,
+        REGISTER_CHANNELS,
+    },
+    stats::NetworkStats,
 };
-use std::{collections::HashSet, iter::FromIterator, net::SocketAddr, time::Duration};
+use player_windows::PlayerWindowsPlugin;
+use stats::StatsPlugin;
+use std::{collections::HashSet, iter::FromIterator, net::SocketAddr};
 
 const PLAYER_COLORS: [Color; 5] = [
     Color::rgb(
@@ -41,136 +56,655 @@ const PLAYER_COLORS: [Color; 5] = [
     ),
 ];
 
+/// The input last sent for each local player, and what it was sent to.
+#[derive(Default)]
+struct SentInput {
+    client_handle: ClientHandle,
+    slots: Vec<ClientSlot>,
+    last_step: Option<u32>,
+    inputs: Vec<PlayerInput>,
+}
+
+impl SentInput {
+    /// Forget the sent input when the server may not have it anymore:
+    /// after a new welcome, when the client's players change, and when one of them respawns.
+    fn forget_if_stale(
+        &mut self,
+        welcomed: bool,
+        client_handle: ClientHandle,
+        display_state: &GameDisplayState,
+    ) {
+        let slots = display_state
+            .clients
+            .get(&client_handle)
+            .cloned()
+            .unwrap_or_default();
+        let last_step = self.last_step;
+        let respawned = display_state.events.iter().any(|(step_number, event)| {
+            // Step numbers wrap around, so compare the distance instead of the numbers
+            let is_new = last_step.map_or(true, |last_step| {
+                step_number.wrapping_sub(last_step) as i32 > 0
+            });
+            is_new
+                && matches!(event, CollisionEvent::KnockedOut(player_id)
+                    if slots.contains(&ClientSlot::Player(*player_id)))
+        });
+        if welcomed || respawned || client_handle != self.client_handle || slots != self.slots {
+            self.inputs.clear();
+        }
+        self.client_handle = client_handle;
+        self.slots = slots;
+        if let Some((step_number, _)) = display_state.events.last() {
+            self.last_step = Some(*step_number);
+        }
+    }
+}
+
+/// Send the input of each local player.
+#[allow(clippy::too_many_arguments)]
 fn player_input(
-    mut state: Local<PlayerInput>,
-    input: Res<Input<KeyCode>>,
+    mut sent: Local<SentInput>,
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    settings: Res<Settings>,
+    status: Res<HandshakeStatus>,
     mut client: ResMut<Client<GameWorld>>,
     mut net: ResMut<NetworkResource>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut stats: ResMut<NetworkStats>,
 ) {
     if let ClientStageMut::Ready(mut ready_client) = client.stage_mut() {
-        let player_id = PlayerId(ready_client.client_id() as u8);
-
-        let player_input = &PlayerInput {
-            jump: input.pressed(KeyCode::Up),
-            left: input.pressed(KeyCode::Left),
-            right: input.pressed(KeyCode::Right),
+        let client_handle = ready_client.client_id() as ClientHandle;
+        let is_spectating = {
+            let display_state: &GameDisplayState = &ready_client.display_state();
+            sent.forget_if_stale(status.is_changed(), client_handle, display_state);
+            display_state.is_spectating(client_handle)
         };
-
-        if player_input.jump != state.jump {
-            ready_client.issue_command(
-                GameCommand::Input(player_id, PlayerCommand::Jump, player_input.jump),
-                &mut WrappedNetworkResource(&mut *net),
-            );
-        }
-        if player_input.left != state.left {
-            ready_client.issue_command(
-                GameCommand::Input(player_id, PlayerCommand::Left, player_input.left),
-                &mut WrappedNetworkResource(&mut *net),
-            );
+        // The world drops the input of spectators
+        if is_spectating {
+            return;
         }
-        if player_input.right != state.right {
-            ready_client.issue_command(
-                GameCommand::Input(player_id, PlayerCommand::Right, player_input.right),
-                &mut WrappedNetworkResource(&mut *net),
-            );
+        let devices = InputDevices {
+            keys: &keys,
+            mouse_buttons: &mouse_buttons,
+            gamepad_buttons: &gamepad_buttons,
+            gamepad_axes: &gamepad_axes,
+        };
+        let local_players = local_players(&settings);
+        sent.inputs
+            .resize(local_players as usize, PlayerInput::default());
+        for local_player in 0..local_players {
+            let player_input = settings.bindings[local_player as usize].input(&devices);
+            let previous = &mut sent.inputs[local_player as usize];
+            // One command with the whole input, only when it has changed
+            if player_input != *previous {
+                let command = GameCommand::SetInput(client_handle, local_player, player_input);
+                ready_client.issue_command(
+                    command,
+                    &mut GameNetwork::client(&mut *net, &mut *received, &mut *stats),
+                );
+                *previous = player_input;
+            }
         }
-        *state = *player_input;
     }
 }
 
 fn main() {
+    let settings = Settings::from_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
     App::build()
-        // You can optionally override some message channel settings
-        // There is `CommandChannelSettings`, `SnapshotChannelSettings`, and `ClockSyncChannelSettings`
-        // Make sure you apply the same settings for both client and server.
-        .insert_resource(CommandChannelSettings(MessageChannelSettings {
-            channel: 0,
-            channel_mode: MessageChannelMode::Compressed {
-                reliability_settings: ReliableChannelSettings {
-                    bandwidth: 4096,
-                    recv_window_size: 1024,
-                    send_window_size: 1024,
-                    burst_bandwidth: 1024,
-                    init_send: 512,
-                    wakeup_time: Duration::from_millis(100),
-                    initial_rtt: Duration::from_millis(200),
-                    max_rtt: Duration::from_secs(2),
-                    rtt_update_factor: 0.1,
-                    rtt_resend_factor: 1.5,
-                },
-                max_chunk_len: 1024,
-            },
-            message_buffer_size: 64,
-            packet_buffer_size: 64,
-        }))
+        .add_plugin(NetworkSettingsPlugin::new(&settings))
         .add_plugins(DefaultPlugins)
-        .add_startup_system(setup_scene.system())
-        .add_plugin(CrystalOrbClientPlugin::<GameWorld>::new(
-            orbgame_shared::crystal_orb_config(),
-        ))
-        .add_startup_system(setup_network.system())
+        .add_plugin(NetworkingPlugin::default())
+        .insert_resource(Client::<GameWorld>::new(settings.crystal_orb_config()))
+        .init_resource::<ReceivedSnapshots>()
+        .init_resource::<NetworkStats>()
+        .insert_resource(settings)
+        .insert_resource(HandshakeStatus::Pending)
+        .init_resource::<CameraMode>()
+        .init_resource::<StateText>()
+        .add_plugin(StatsPlugin)
+        .add_plugin(PlayerWindowsPlugin)
+        .add_startup_system(setup_ui.system())
+        .add_startup_system_to_stage(
+            StartupStage::PostStartup,
+            setup_network.system().after(REGISTER_CHANNELS),
+        )
+        .add_system(update_client.system())
+        .add_system(handshake.system())
         .add_system(player_input.system())
+        .add_system(spectator_controls.system())
+        .add_system(spectator_camera.system())
         .add_system(bevy::input::system::exit_on_esc_system.system())
         .add_system(show_state.system())
+        .add_system(arena_view_lifecycle.system())
         .add_system(player_view_lifecycle.system())
+        .add_system(pickup_view_lifecycle.system())
         .add_system(view.system())
+        .add_system(collision_effects.system())
+        .add_system(bounce.system())
         .run();
 }
 
-/// Player component.
-struct Player(PlayerId);
+/// Player component. The player's id, and the radius its mesh was made with.
+struct Player(PlayerId, f32);
+
+/// Makes a player's view pulse for a moment after a collision. Seconds left of the pulse.
+struct Bounce(f32);
+
+/// How long a player's view pulses after a collision.
+const BOUNCE_SECONDS: f32 = 0.2;
 
-fn setup_scene(
+/// Component for entities that show the arena.
+struct ArenaView;
+
+/// Component for the camera of the main window. It shows the whole arena, follows the first
+/// local player if there are several, see [`player_windows`], or is moved by a spectator.
+struct ArenaCamera;
+
+/// What the camera shows while spectating.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CameraMode {
+    /// Moved with the arrow keys, and Page Up and Page Down for zooming.
+    Free,
+    /// Follows a player.
+    Follow(PlayerId),
+}
+
+impl Default for CameraMode {
+    fn default() -> Self {
+        CameraMode::Free
+    }
+}
+
+/// How fast the free camera moves, in units per second.
+const FREE_CAMERA_SPEED: f32 = 300.0;
+
+/// Where the camera is when following a player, relative to the player.
+const FOLLOW_CAMERA_OFFSET: [f32; 3] = [0.0, 40.0, 300.0];
+
+/// Component for entities that show a pickup: the arena, and the pickup's index in the arena.
+struct PickupView(ArenaId, usize);
+
+/// How deep the arena's shapes are in the 3D view.
+const ARENA_DEPTH: f32 = 20.0;
+
+/// What [`show_state`] shows.
+#[derive(Default)]
+struct StateText(String);
+
+/// Result of the handshake with the server.
+enum HandshakeStatus {
+    Pending,
+    Accepted,
+    Rejected(String),
+}
+
+/// Show the arena of the world, and rebuild the view when the world switches arena.
+fn arena_view_lifecycle(
+    mut shown: Local<Option<ArenaId>>,
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    client: Res<Client<GameWorld>>,
+    views: Query<Entity, With<ArenaView>>,
+) {
+    // Until the world has arrived from the server, show the arena that worlds start in
+    let (arena_id, arena) = match client.stage() {
+        ClientStage::Ready(client) => {
+            let display_state: &GameDisplayState = &client.display_state();
+            if *shown == Some(display_state.arena_id) {
+                return;
+            }
+            (display_state.arena_id, display_state.arena.clone())
+        }
+        _ if shown.is_some() => return,
+        _ => {
+            let arena = Arena::default();
+            (arena.id(), arena.into())
+        }
+    };
+    info!("Showing arena {}", arena.name);
+    for entity in views.iter() {
+        commands.entity(entity).despawn();
+    }
+    spawn_arena(&mut commands, &mut meshes, &mut materials, &arena);
+    *shown = Some(arena_id);
+}
+
+/// Create the light, camera and meshes for an arena.
+fn spawn_arena(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    arena: &Arena,
 ) {
+    let center = arena.bounds.center();
+    let size = arena.bounds.size();
     // light
-    commands.spawn_bundle(LightBundle {
-        light: Light {
-            intensity: 10000.0,
-            range: 200.0,
+    commands
+        .spawn_bundle(LightBundle {
+            light: Light {
+                intensity: 10000.0,
+                range: size.x.max(size.y) + 20.0,
+                ..Default::default()
+            },
+            transform: Transform::from_xyz(center.x, arena.bounds.max[1] + 10.0, 0.0)
+                .looking_at(Vec3::new(center.x, arena.bounds.min[1], 0.0), Vec3::Y),
             ..Default::default()
-        },
-        transform: Transform::from_xyz(90.0, 110.0, 0.0)
-            .looking_at(Vec3::new(90.0, 0.0, 0.0), Vec3::Y),
-        ..Default::default()
-    });
+        })
+        .insert(ArenaView);
     // camera
-    commands.spawn_bundle(PerspectiveCameraBundle {
-        transform: Transform::from_xyz(90.0, 70.0, 180.0)
-            .looking_at(Vec3::new(90.0, 50.0, 0.0), Vec3::Y),
-        ..Default::default()
-    });
+    commands
+        .spawn_bundle(PerspectiveCameraBundle {
+            transform: Transform::from_xyz(center.x, center.y + 20.0, size.x)
+                .looking_at(Vec3::new(center.x, center.y, 0.0), Vec3::Y),
+            ..Default::default()
+        })
+        .insert(ArenaView)
+        .insert(ArenaCamera);
     // floor
-    commands.spawn_bundle(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Plane { size: 180.0 })),
-        material: materials.add(Color::rgb(0.3, 0.4, 0.5).into()),
-        transform: Transform::from_xyz(90.0, 0.0, 0.0),
-        ..Default::default()
-    });
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Plane { size: size.x })),
+            material: materials.add(Color::rgb(0.3, 0.4, 0.5).into()),
+            transform: Transform::from_xyz(center.x, arena.bounds.min[1], 0.0),
+            ..Default::default()
+        })
+        .insert(ArenaView);
+    // static shapes
+    let material = materials.add(Color::rgb(0.5, 0.5, 0.6).into());
+    for static_shape in arena.shapes.iter() {
+        let shape_transform = Transform {
+            translation: Vec3::new(static_shape.position[0], static_shape.position[1], 0.0),
+            rotation: Quat::from_rotation_z(static_shape.rotation),
+            ..Default::default()
+        };
+        for (mesh, transform) in shape_meshes(&static_shape.shape, ARENA_DEPTH) {
+            commands
+                .spawn_bundle(PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: material.clone(),
+                    transform: shape_transform * transform,
+                    ..Default::default()
+                })
+                .insert(ArenaView);
+        }
+    }
+    // zones, drawn as thin shapes behind the players
+    let hill_material = materials.add(Color::rgb(0.9, 0.8, 0.2).into());
+    let hazard_material = materials.add(Color::rgb(0.9, 0.2, 0.1).into());
+    let goal_material = materials.add(Color::rgb(0.2, 0.9, 0.3).into());
+    let checkpoint_material = materials.add(Color::rgb(0.3, 0.6, 0.9).into());
+    for zone in arena.zones.iter() {
+        let zone_transform = Transform {
+            translation: Vec3::new(zone.position[0], zone.position[1], -ARENA_DEPTH / 2.0),
+            rotation: Quat::from_rotation_z(zone.rotation),
+            ..Default::default()
+        };
+        let material = match zone.kind {
+            ZoneKind::Hill => hill_material.clone(),
+            ZoneKind::Hazard => hazard_material.clone(),
+            ZoneKind::Goal => goal_material.clone(),
+            ZoneKind::Checkpoint => checkpoint_material.clone(),
+        };
+        for (mesh, transform) in shape_meshes(&zone.shape, 1.0) {
+            commands
+                .spawn_bundle(PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: material.clone(),
+                    transform: zone_transform * transform,
+                    ..Default::default()
+                })
+                .insert(ArenaView);
+        }
+    }
 }
 
-fn setup_network(mut net: ResMut<NetworkResource>) {
-    let ip_address =
-        bevy_networking_turbulence::find_my_ip_address().expect("can't find ip address");
-    let socket_address = SocketAddr::new(ip_address, orbgame_shared::SERVER_PORT);
+/// Create meshes for a shape, in the shape's local coordinates.
+fn shape_meshes(shape: &Shape, depth: f32) -> Vec<(Mesh, Transform)> {
+    match shape {
+        Shape::Cuboid { half_extents } => vec![(
+            Mesh::from(shape::Box::new(
+                half_extents[0] * 2.0,
+                half_extents[1] * 2.0,
+                depth,
+            )),
+            Transform::identity(),
+        )],
+        Shape::Ball { radius } => vec![(
+            Mesh::from(shape::Icosphere {
+                radius: *radius,
+                subdivisions: 3,
+            }),
+            Transform::identity(),
+        )],
+        shape => segment_meshes(&shape.segments().unwrap_or_default(), depth),
+    }
+}
+
+/// Create thin boxes along connected line segments.
+fn segment_meshes(points: &[[f32; 2]], depth: f32) -> Vec<(Mesh, Transform)> {
+    points
+        .windows(2)
+        .map(|segment| {
+            let start = Vec2::new(segment[0][0], segment[0][1]);
+            let end = Vec2::new(segment[1][0], segment[1][1]);
+            let delta = end - start;
+            let middle = (start + end) / 2.0;
+            (
+                Mesh::from(shape::Box::new(delta.length(), 1.0, depth)),
+                Transform {
+                    translation: Vec3::new(middle.x, middle.y, 0.0),
+                    rotation: Quat::from_rotation_z(delta.y.atan2(delta.x)),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect()
+}
+
+/// The camera for the UI, such as the statistics overlay.
+fn setup_ui(mut commands: Commands) {
+    commands.spawn_bundle(UiCameraBundle::default());
+}
+
+/// Run the client's part of the netcode: exchange messages with the server, and simulate.
+fn update_client(
+    mut client: ResMut<Client<GameWorld>>,
+    mut net: ResMut<NetworkResource>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    time: Res<Time>,
+) {
+    client.update(
+        time.delta_seconds_f64(),
+        time.seconds_since_startup(),
+        &mut GameNetwork::client(&mut *net, &mut *received, &mut *stats)
+            .received_at(time.seconds_since_startup()),
+    );
+}
+
+fn setup_network(mut net: ResMut<NetworkResource>, settings: Res<Settings>) {
+    let ip_address = settings.address.unwrap_or_else(|| {
+        bevy_networking_turbulence::find_my_ip_address().expect("can't find ip address")
+    });
+    let socket_address = SocketAddr::new(ip_address, settings.port);
     info!("Connecting to {}", socket_address);
     net.connect(socket_address);
 }
 
-fn show_state(mut previous: Local<String>, client: ResMut<Client<GameWorld>>) {
-    use crystalorb::client::stage::Stage;
-    let text = match client.stage() {
-        Stage::SyncingClock(c) => {
-            format!("SyncingClock {}/{}", c.sample_count(), c.samples_needed())
+/// How many players this client controls: one for each set of bindings.
+/// There is at least one, see [`Settings::validate`].
+fn local_players(settings: &Settings) -> LocalPlayer {
+    settings.bindings.len().min(MAX_LOCAL_PLAYERS as usize) as LocalPlayer
+}
+
+/// Send hello to the server, and handle its answer. Once accepted, acknowledge snapshots.
+#[allow(clippy::too_many_arguments)]
+fn handshake(
+    mut hellos_sent: Local<HashSet<ConnectionHandle>>,
+    mut status: ResMut<HandshakeStatus>,
+    mut net: ResMut<NetworkResource>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    fingerprint: Res<Fingerprint>,
+    settings: Res<Settings>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    let mut rejected = Vec::new();
+    let mut replies = Vec::new();
+    let hellos: Vec<ConnectionHandle> = net
+        .connections
+        .keys()
+        .copied()
+        .filter(|handle| hellos_sent.insert(*handle))
+        .collect();
+    let messages = GameNetwork::client(&mut *net, &mut *received, &mut *stats).recv_handshakes();
+    for (handle, message) in messages {
+        match message {
+            HandshakeMessage::Welcome => {
+                info!("Server accepted the connection");
+                // The server numbers its snapshots from its own start
+                received.reset();
+                *status = HandshakeStatus::Accepted;
+            }
+            HandshakeMessage::Rejected { reason } => {
+                error!("Server rejected the connection: {}", reason);
+                *status = HandshakeStatus::Rejected(reason);
+                rejected.push(handle);
+            }
+            HandshakeMessage::Ping { sent_at } => {
+                replies.push((handle, HandshakeMessage::Pong { sent_at }))
+            }
+            HandshakeMessage::Pong { sent_at } => {
+                stats.record_round_trip_time(handle, now - sent_at)
+            }
+            HandshakeMessage::Hello { .. }
+            | HandshakeMessage::Join { .. }
+            | HandshakeMessage::Spectate
+            | HandshakeMessage::Acknowledge { .. } => {
+                warn!("Unexpected handshake from server: {:?}", message);
+            }
+        }
+    }
+    let mut network = GameNetwork::client(&mut *net, &mut *received, &mut *stats);
+    for handle in hellos {
+        debug!("Sending hello to server");
+        // No players means spectating
+        let local_players = if settings.spectate {
+            0
+        } else {
+            local_players(&settings)
+        };
+        let hello = HandshakeMessage::hello(*fingerprint, local_players);
+        if network.send_handshake(handle, hello).is_some() {
+            error!("Can't send hello to server");
         }
-        Stage::SyncingInitialState(_) => "SyncingInitialState".to_string(),
-        Stage::Ready(_) => "Ready".to_string(),
+    }
+    for (handle, reply) in replies {
+        if network.send_handshake(handle, reply).is_some() {
+            warn!("Can't answer ping from server");
+        }
+    }
+    for handle in rejected {
+        net.connections.remove(&handle);
+        stats.forget_connection(handle);
+    }
+
+    // Let the server delta-encode snapshots against the ones that have arrived
+    let snapshots = received.take_acknowledgements();
+    if matches!(*status, HandshakeStatus::Accepted) && !snapshots.is_empty() {
+        let handles: Vec<ConnectionHandle> = net.connections.keys().copied().collect();
+        let mut network = GameNetwork::client(&mut *net, &mut *received, &mut *stats);
+        for handle in handles {
+            let acknowledge = HandshakeMessage::Acknowledge {
+                snapshots: snapshots.clone(),
+            };
+            if network.send_handshake(handle, acknowledge).is_some() {
+                warn!("Can't acknowledge snapshots");
+            }
+        }
+    }
+}
+
+/// Log the handshake and crystalorb stage when they change, and keep them for the statistics overlay.
+fn show_state(
+    mut state_text: ResMut<StateText>,
+    client: ResMut<Client<GameWorld>>,
+    handshake: Res<HandshakeStatus>,
+) {
+    use crystalorb::client::stage::Stage;
+    let text = match (&*handshake, client.stage()) {
+        (HandshakeStatus::Rejected(reason), _) => format!("Rejected by server: {}", reason),
+        (HandshakeStatus::Pending, _) => "Waiting for server".to_string(),
+        (HandshakeStatus::Accepted, stage) => match stage {
+            Stage::SyncingClock(c) => {
+                format!("SyncingClock {}/{}", c.sample_count(), c.samples_needed())
+            }
+            Stage::SyncingInitialState(_) => "SyncingInitialState".to_string(),
+            Stage::Ready(c) => {
+                let client_handle = c.client_id() as ClientHandle;
+                let display_state: &GameDisplayState = &c.display_state();
+                let phase = match display_state.phase {
+                    MatchPhase::Playing | MatchPhase::RoundEnd => {
+                        format!("{} {}", display_state.phase, display_state.round)
+                    }
+                    phase => phase.to_string(),
+                };
+                // Whole seconds, so the text only changes once per second
+                let phase = format!(
+                    "{}, {}, {:.0}s left",
+                    display_state.mode,
+                    phase,
+                    display_state.seconds_left.ceil()
+                );
+                match display_state.clients.get(&client_handle) {
+                    Some(slots) if slots.is_empty() => format!("Spectating. {}", phase),
+                    Some(slots) => {
+                        // One part for each local player
+                        let players: Vec<String> = slots
+                            .iter()
+                            .map(|slot| match slot {
+                                ClientSlot::Rejected(error) => format!("no player: {}", error),
+                                ClientSlot::Player(player_id) => {
+                                    let score =
+                                        display_state.scores.get(player_id).copied().unwrap_or(0);
+                                    let marked = if display_state.marked_player == Some(*player_id)
+                                    {
+                                        ", it"
+                                    } else {
+                                        ""
+                                    };
+                                    format!("{} score {}{}", player_id, score, marked)
+                                }
+                            })
+                            .collect();
+                        format!("Ready. {}. {}", phase, players.join("; "))
+                    }
+                    None => format!("Ready. {}", phase),
+                }
+            }
+        },
     };
-    if *previous != text {
+    if state_text.0 != text {
         info!("State: {}", text);
-        *previous = text;
+        state_text.0 = text;
+    }
+}
+
+/// Keys for spectators: J to join when there is room, and while spectating,
+/// Tab to follow the next player and F for the free camera.
+/// Players press J to go back to spectating.
+fn spectator_controls(
+    keys: Res<Input<KeyCode>>,
+    mut mode: ResMut<CameraMode>,
+    client: Res<Client<GameWorld>>,
+    settings: Res<Settings>,
+    mut net: ResMut<NetworkResource>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    let client = match client.stage() {
+        ClientStage::Ready(client) => client,
+        _ => return,
+    };
+    let display_state: &GameDisplayState = &client.display_state();
+    let spectating = display_state.is_spectating(client.client_id() as ClientHandle);
+
+    if keys.just_pressed(KeyCode::J) {
+        let message = if spectating {
+            info!("Asking to join");
+            HandshakeMessage::Join {
+                local_players: local_players(&settings),
+            }
+        } else {
+            info!("Asking to spectate");
+            HandshakeMessage::Spectate
+        };
+        let handles: Vec<ConnectionHandle> = net.connections.keys().copied().collect();
+        let mut network = GameNetwork::client(&mut *net, &mut *received, &mut *stats);
+        for handle in handles {
+            if network.send_handshake(handle, message.clone()).is_some() {
+                error!("Can't send {:?} to server", message);
+            }
+        }
+    }
+
+    if !spectating {
+        *mode = CameraMode::Free;
+        return;
+    }
+    if keys.just_pressed(KeyCode::F) {
+        *mode = CameraMode::Free;
+    }
+    if keys.just_pressed(KeyCode::Tab) {
+        // The player after the followed one, or the first one
+        let mut player_ids = display_state.player_positions.keys();
+        let next = match *mode {
+            CameraMode::Follow(followed) => player_ids.clone().find(|id| **id > followed),
+            CameraMode::Free => None,
+        }
+        .or_else(|| player_ids.next());
+        if let Some(player_id) = next {
+            info!("Following player {}", player_id);
+            *mode = CameraMode::Follow(*player_id);
+        }
+    }
+}
+
+/// Move the camera of a spectator: with the keys in free mode, or after the followed player.
+fn spectator_camera(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    mut mode: ResMut<CameraMode>,
+    client: Res<Client<GameWorld>>,
+    mut cameras: Query<&mut Transform, With<ArenaCamera>>,
+) {
+    let client = match client.stage() {
+        ClientStage::Ready(client) => client,
+        _ => return,
+    };
+    let display_state: &GameDisplayState = &client.display_state();
+    if !display_state.is_spectating(client.client_id() as ClientHandle) {
+        return;
+    }
+    for mut transform in cameras.iter_mut() {
+        match *mode {
+            CameraMode::Free => {
+                let direction = Vec3::new(
+                    keys.pressed(KeyCode::Right) as i32 as f32
+                        - keys.pressed(KeyCode::Left) as i32 as f32,
+                    keys.pressed(KeyCode::Up) as i32 as f32
+                        - keys.pressed(KeyCode::Down) as i32 as f32,
+                    keys.pressed(KeyCode::PageDown) as i32 as f32
+                        - keys.pressed(KeyCode::PageUp) as i32 as f32,
+                );
+                transform.translation += direction * FREE_CAMERA_SPEED * time.delta_seconds();
+            }
+            CameraMode::Follow(player_id) => {
+                let position = match display_state.player_positions.get(&player_id) {
+                    Some(position) => position,
+                    None => {
+                        // The player has left
+                        *mode = CameraMode::Free;
+                        return;
+                    }
+                };
+                let target = Vec3::new(
+                    position.translation.vector.x,
+                    position.translation.vector.y,
+                    0.0,
+                );
+                *transform = Transform::from_translation(target + Vec3::from(FOLLOW_CAMERA_OFFSET))
+                    .looking_at(target, Vec3::Y);
+            }
+        }
     }
 }
 
@@ -180,25 +714,31 @@ fn player_view_lifecycle(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     client: Res<Client<GameWorld>>,
-    players: Query<&Player>,
+    players: Query<(Entity, &Player)>,
 ) {
     match client.stage() {
         ClientStage::Ready(client) => {
             let display_state = client.display_state();
 
-            let current_views = HashSet::<PlayerId>::from_iter(players.iter().map(|p| p.0));
+            let radius = display_state.player_radius;
             let player_ids = HashSet::from_iter(display_state.player_positions.keys().copied());
 
-            for player_id in current_views.difference(&player_ids) {
-                warn!("Should remove player {} - not implemented", player_id);
-                //commands.remove_resource()
+            // Views made for another radius are replaced, the rules may have changed
+            let mut current_views = HashSet::new();
+            for (entity, player) in players.iter() {
+                if !player_ids.contains(&player.0) || player.1 != radius {
+                    info!("Removing view for player {}", player.0);
+                    commands.entity(entity).despawn();
+                } else {
+                    current_views.insert(player.0);
+                }
             }
             for player_id in player_ids.difference(&current_views) {
                 info!("Creating view for player {}", player_id);
                 commands
                     .spawn_bundle(PbrBundle {
                         mesh: meshes.add(Mesh::from(shape::Icosphere {
-                            radius: 10.0,
+                            radius,
                             subdivisions: 3,
                         })),
                         material: materials
@@ -206,21 +746,127 @@ fn player_view_lifecycle(
                         transform: Transform::from_xyz(0.0, 0.5, 0.0),
                         ..Default::default()
                     })
-                    .insert(Player(*player_id));
+                    .insert(Player(*player_id, radius))
+                    .insert(Bounce(0.0));
             }
         }
         _ => (),
     }
 }
 
+/// Make sure we have views for the pickups that can be picked up, and no others.
+fn pickup_view_lifecycle(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    client: Res<Client<GameWorld>>,
+    views: Query<(Entity, &PickupView)>,
+) {
+    let client = match client.stage() {
+        ClientStage::Ready(client) => client,
+        _ => return,
+    };
+    let display_state: &GameDisplayState = &client.display_state();
+    let is_visible = |view: &PickupView| {
+        view.0 == display_state.arena_id
+            && display_state
+                .pickups
+                .iter()
+                .any(|pickup| pickup.index == view.1)
+    };
+    for (entity, view) in views.iter() {
+        if !is_visible(view) {
+            commands.entity(entity).despawn();
+        }
+    }
+    for pickup in display_state.pickups.iter() {
+        if views
+            .iter()
+            .any(|(_, view)| view.0 == display_state.arena_id && view.1 == pickup.index)
+        {
+            continue;
+        }
+        let color = match pickup.kind {
+            PickupKind::SpeedBoost => Color::rgb(0.2, 0.9, 0.9),
+            PickupKind::ExtraJump => Color::rgb(0.9, 0.9, 0.9),
+            PickupKind::HeavierMass => Color::rgb(0.3, 0.3, 0.3),
+        };
+        commands
+            .spawn_bundle(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Icosphere {
+                    radius: PICKUP_RADIUS,
+                    subdivisions: 2,
+                })),
+                material: materials.add(color.into()),
+                transform: Transform::from_xyz(pickup.position[0], pickup.position[1], 0.0),
+                ..Default::default()
+            })
+            .insert(PickupView(display_state.arena_id, pickup.index));
+    }
+}
+
 fn view(client: Res<Client<GameWorld>>, mut query: Query<(&Player, &mut Transform)>) {
     if let ClientStage::Ready(client) = client.stage() {
         let display_state = client.display_state();
         for (player, mut transform) in query.iter_mut() {
-            let pos = display_state.player_positions[&player.0];
+            let pos = match display_state.player_positions.get(&player.0) {
+                Some(pos) => pos,
+                // The view is removed by player_view_lifecycle
+                None => continue,
+            };
             transform.translation =
                 Vec3::new(pos.translation.vector.x, pos.translation.vector.y, 0.0);
             transform.rotation = Quat::from_rotation_z(pos.rotation.angle());
         }
     }
 }
+
+/// Start effects for collision events that have not been handled yet.
+fn collision_effects(
+    mut last_step: Local<Option<u32>>,
+    client: Res<Client<GameWorld>>,
+    mut players: Query<(&Player, &mut Bounce)>,
+) {
+    let client = match client.stage() {
+        ClientStage::Ready(client) => client,
+        _ => return,
+    };
+    let display_state: &GameDisplayState = &client.display_state();
+    for (step_number, event) in display_state.events.iter() {
+        // Step numbers wrap around, so compare the distance instead of the numbers
+        if let Some(last_step) = *last_step {
+            if step_number.wrapping_sub(last_step) as i32 <= 0 {
+                continue;
+            }
+        }
+        match event {
+            CollisionEvent::KnockedOut(player_id) => info!("Player {} is knocked out", player_id),
+            CollisionEvent::EnteredZone(player_id, ZoneKind::Hill, _) => {
+                debug!("Player {} entered the hill", player_id)
+            }
+            CollisionEvent::EnteredZone(player_id, ZoneKind::Goal, _) => {
+                info!("Player {} reached the goal", player_id)
+            }
+            CollisionEvent::PickedUp(player_id, kind, _) => {
+                info!("Player {} picked up {:?}", player_id, kind)
+            }
+            _ => {}
+        }
+        let event_players = event.players();
+        for (player, mut bounce) in players.iter_mut() {
+            if event_players.contains(&player.0) {
+                bounce.0 = BOUNCE_SECONDS;
+            }
+        }
+    }
+    if let Some((step_number, _)) = display_state.events.last() {
+        *last_step = Some(*step_number);
+    }
+}
+
+fn bounce(time: Res<Time>, mut query: Query<(&mut Bounce, &mut Transform)>) {
+    for (mut bounce, mut transform) in query.iter_mut() {
+        bounce.0 = (bounce.0 - time.delta_seconds()).max(0.0);
+        transform.scale = Vec3::splat(1.0 + 0.3 * bounce.0 / BOUNCE_SECONDS);
+    }
+}
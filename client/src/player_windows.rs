@@ -0,0 +1,241 @@
+//! A view for each local player. The first player uses the main window, every further player
+//! gets a window of its own. With several local players, each camera follows its player;
+//! a single player sees the whole arena.
+
+use crate::{local_players, ArenaCamera, FOLLOW_CAMERA_OFFSET};
+use orbgame_shared::{
+    arena::Arena,
+    bevy::{
+        prelude::*,
+        render::{
+            camera::{ActiveCameras, Camera},
+            pass::{
+                LoadOp, Operations, PassDescriptor, RenderPassDepthStencilAttachment,
+                TextureAttachment,
+            },
+            render_graph::{
+                base::MainPass, CameraNode, PassNode, RenderGraph, WindowSwapChainNode,
+                WindowTextureNode,
+            },
+            texture::{TextureDescriptor, TextureFormat, TextureUsage},
+        },
+        window::{CreateWindow, WindowId},
+    },
+    config::Settings,
+    crystalorb_bevy_networking_turbulence::crystalorb::client::{stage::Stage, Client},
+    game::{ClientHandle, ClientSlot, GameDisplayState, GameWorld, LocalPlayer},
+};
+
+pub struct PlayerWindowsPlugin;
+
+/// Component for the camera of a local player.
+pub struct PlayerCamera(pub LocalPlayer);
+
+/// The windows that have been asked for, but whose cameras have not been set up yet.
+#[derive(Default)]
+struct PendingWindows(Vec<(LocalPlayer, WindowId)>);
+
+impl Plugin for PlayerWindowsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<PendingWindows>()
+            .add_startup_system(create_windows.system())
+            .add_system(set_up_windows.system())
+            .add_system(follow_players.system());
+    }
+}
+
+/// Ask for a window for each local player after the first.
+fn create_windows(
+    settings: Res<Settings>,
+    mut pending: ResMut<PendingWindows>,
+    mut create_window_events: EventWriter<CreateWindow>,
+) {
+    for local_player in 1..local_players(&settings) {
+        let id = WindowId::new();
+        create_window_events.send(CreateWindow {
+            id,
+            descriptor: WindowDescriptor {
+                title: format!("orbgame player {}", local_player + 1),
+                ..Default::default()
+            },
+        });
+        pending.0.push((local_player, id));
+    }
+}
+
+/// Render to the windows that have been created, each from its player's camera.
+fn set_up_windows(
+    mut commands: Commands,
+    mut pending: ResMut<PendingWindows>,
+    windows: Res<Windows>,
+    msaa: Res<Msaa>,
+    mut render_graph: ResMut<RenderGraph>,
+    mut active_cameras: ResMut<ActiveCameras>,
+) {
+    let (created, waiting): (Vec<_>, Vec<_>) = pending
+        .0
+        .drain(..)
+        .partition(|(_, id)| windows.get(*id).is_some());
+    pending.0 = waiting;
+    for (local_player, window_id) in created {
+        let camera_name = format!("player_{}_camera", local_player);
+        let swap_chain = format!("player_{}_swap_chain", local_player);
+        let depth_texture = format!("player_{}_depth_texture", local_player);
+        let pass = format!("player_{}_pass", local_player);
+
+        render_graph.add_system_node(camera_name.clone(), CameraNode::new(camera_name.clone()));
+        render_graph.add_node(swap_chain.clone(), WindowSwapChainNode::new(window_id));
+        render_graph.add_node(
+            depth_texture.clone(),
+            WindowTextureNode::new(
+                window_id,
+                TextureDescriptor {
+                    format: TextureFormat::Depth32Float,
+                    usage: TextureUsage::OUTPUT_ATTACHMENT,
+                    sample_count: msaa.samples,
+                    ..Default::default()
+                },
+            ),
+        );
+        let mut pass_node = PassNode::<&MainPass>::new(PassDescriptor {
+            color_attachments: vec![msaa.color_attachment(
+                TextureAttachment::Input("color_attachment".to_string()),
+                TextureAttachment::Input("color_resolve_target".to_string()),
+                Operations {
+                    load: LoadOp::Clear(Color::rgb(0.1, 0.1, 0.1)),
+                    store: true,
+                },
+            )],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                attachment: TextureAttachment::Input("depth".to_string()),
+                depth_ops: Some(Operations {
+                    load: LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+            sample_count: msaa.samples,
+        });
+        pass_node.add_camera(&camera_name);
+        render_graph.add_node(pass.clone(), pass_node);
+
+        let color_slot = if msaa.samples > 1 {
+            let sampled = format!("player_{}_sampled_color", local_player);
+            render_graph.add_node(
+                sampled.clone(),
+                WindowTextureNode::new(
+                    window_id,
+                    TextureDescriptor {
+                        format: TextureFormat::default(),
+                        usage: TextureUsage::OUTPUT_ATTACHMENT,
+                        sample_count: msaa.samples,
+                        ..Default::default()
+                    },
+                ),
+            );
+            render_graph
+                .add_slot_edge(
+                    sampled,
+                    WindowTextureNode::OUT_TEXTURE,
+                    pass.clone(),
+                    "color_attachment",
+                )
+                .unwrap();
+            "color_resolve_target"
+        } else {
+            "color_attachment"
+        };
+        render_graph
+            .add_slot_edge(
+                swap_chain,
+                WindowSwapChainNode::OUT_TEXTURE,
+                pass.clone(),
+                color_slot,
+            )
+            .unwrap();
+        render_graph
+            .add_slot_edge(
+                depth_texture,
+                WindowTextureNode::OUT_TEXTURE,
+                pass.clone(),
+                "depth",
+            )
+            .unwrap();
+        render_graph
+            .add_node_edge(camera_name.clone(), pass)
+            .unwrap();
+
+        active_cameras.add(&camera_name);
+        // The windows open before the world has arrived, the camera follows the player later
+        let arena = Arena::default();
+        let center = arena.bounds.center();
+        commands
+            .spawn_bundle(PerspectiveCameraBundle {
+                camera: Camera {
+                    name: Some(camera_name),
+                    window: window_id,
+                    ..Default::default()
+                },
+                transform: Transform::from_xyz(center.x, center.y + 20.0, arena.bounds.size().x)
+                    .looking_at(Vec3::new(center.x, center.y, 0.0), Vec3::Y),
+                ..Default::default()
+            })
+            .insert(PlayerCamera(local_player));
+        info!("Opened the window of local player {}", local_player + 1);
+    }
+}
+
+/// Move each local player's camera after its player, if the client has several players.
+/// The main window's camera is the first player's, see [`ArenaCamera`].
+fn follow_players(
+    settings: Res<Settings>,
+    client: Res<Client<GameWorld>>,
+    mut cameras: QuerySet<(
+        Query<&mut Transform, With<ArenaCamera>>,
+        Query<(&PlayerCamera, &mut Transform)>,
+    )>,
+) {
+    if local_players(&settings) < 2 {
+        return;
+    }
+    let client = match client.stage() {
+        Stage::Ready(client) => client,
+        _ => return,
+    };
+    let client_handle = client.client_id() as ClientHandle;
+    let display_state: &GameDisplayState = &client.display_state();
+    // Spectators move the main camera themselves
+    if display_state.is_spectating(client_handle) {
+        return;
+    }
+    let slots = match display_state.clients.get(&client_handle) {
+        Some(slots) => slots,
+        None => return,
+    };
+    let follow = |local_player: LocalPlayer| {
+        let player_id = match slots.get(local_player as usize) {
+            Some(ClientSlot::Player(player_id)) => player_id,
+            _ => return None,
+        };
+        let position = display_state.player_positions.get(player_id)?;
+        let target = Vec3::new(
+            position.translation.vector.x,
+            position.translation.vector.y,
+            0.0,
+        );
+        Some(
+            Transform::from_translation(target + Vec3::from(FOLLOW_CAMERA_OFFSET))
+                .looking_at(target, Vec3::Y),
+        )
+    };
+    if let Some(followed) = follow(0) {
+        for mut transform in cameras.q0_mut().iter_mut() {
+            *transform = followed;
+        }
+    }
+    for (camera, mut transform) in cameras.q1_mut().iter_mut() {
+        if let Some(followed) = follow(camera.0) {
+            *transform = followed;
+        }
+    }
+}
@@ -0,0 +1,398 @@
+//! Netcode statistics: collected once per second, shown in an overlay and optionally
+//! appended to a CSV file. F3 shows and hides the overlay.
+
+use crate::{HandshakeStatus, StateText};
+use orbgame_shared::{
+    bevy::{asset::LoadState, prelude::*},
+    config::Settings,
+    crystalorb_bevy_networking_turbulence::bevy_networking_turbulence::{
+        ConnectionHandle, NetworkResource,
+    },
+    game::codec::ReceivedSnapshots,
+    network::{connection::GameNetwork, HandshakeMessage},
+    stats::{self, NetworkStats},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+/// How often the statistics are collected, and the server is pinged.
+const PERIOD: f64 = 1.0;
+
+pub struct StatsPlugin;
+
+/// The statistics of the last second.
+#[derive(Default, Debug, Clone)]
+pub struct NetStats {
+    /// Bytes per second on all channels, including the channels' overhead.
+    pub bytes_sent: f64,
+    pub bytes_received: f64,
+    pub commands_sent: f64,
+    pub commands: ChannelRates,
+    pub snapshots_received: f64,
+    pub snapshots: ChannelRates,
+    pub handshake: ChannelRates,
+    /// What is not on the other channels: crystalorb's clock sync messages, which are not
+    /// counted on their own, and the channels' overhead.
+    pub clock_sync_and_overhead: ChannelRates,
+    /// Round trip time of the last ping, in seconds.
+    pub round_trip_time: Option<f64>,
+    /// How far the server's clock is ahead of the client's, in seconds, see
+    /// [`stats::ConnectionStats::clock_offset`].
+    pub clock_offset: Option<f64>,
+    /// Snapshots the client has rolled back to per second.
+    pub rollbacks: f64,
+    /// Steps per second, including resimulated and fast-forwarded steps.
+    pub steps: f64,
+}
+
+/// Bytes per second on a channel, see [`stats::ChannelCounts`].
+#[derive(Default, Debug, Clone, Copy)]
+pub struct ChannelRates {
+    pub sent: f64,
+    pub received: f64,
+}
+
+/// Component for the overlay's text.
+struct StatsOverlay;
+
+/// The CSV file that the statistics are written to.
+enum CsvLog {
+    NotOpened,
+    Open(File),
+    /// Opening or writing failed, so the statistics are not written any more.
+    Failed,
+}
+
+impl Default for CsvLog {
+    fn default() -> Self {
+        CsvLog::NotOpened
+    }
+}
+
+/// Counter readings at the last collection.
+#[derive(Default)]
+struct Totals {
+    collected_at: f64,
+    bytes_sent: usize,
+    bytes_received: usize,
+    commands_sent: u64,
+    /// Bytes sent and received on the command, snapshot and handshake channels.
+    channel_bytes: [(u64, u64); 3],
+    snapshots_received: u64,
+    rollbacks: u64,
+    steps: u64,
+}
+
+impl Plugin for StatsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<NetStats>()
+            .add_startup_system(setup_overlay.system())
+            .add_system(ping.system())
+            .add_system(collect.system())
+            .add_system(toggle_overlay.system())
+            .add_system(title_fallback.system());
+    }
+}
+
+fn setup_overlay(mut commands: Commands, asset_server: Res<AssetServer>, settings: Res<Settings>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(5.0),
+                    left: Val::Px(5.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(settings.stats.font.as_str()),
+                    font_size: 16.0,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: settings.stats.overlay,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(StatsOverlay);
+}
+
+fn toggle_overlay(
+    keys: Res<Input<KeyCode>>,
+    mut overlays: Query<&mut Visible, With<StatsOverlay>>,
+) {
+    if keys.just_pressed(KeyCode::F3) {
+        for mut visible in overlays.iter_mut() {
+            visible.is_visible = !visible.is_visible;
+        }
+    }
+}
+
+/// Bevy has no built-in font, so if the overlay's font can't be loaded,
+/// show the statistics in the main window's title while the overlay is shown.
+fn title_fallback(
+    mut original_title: Local<Option<String>>,
+    asset_server: Res<AssetServer>,
+    settings: Res<Settings>,
+    overlays: Query<(&Text, &Visible), With<StatsOverlay>>,
+    mut windows: ResMut<Windows>,
+) {
+    let window = match windows.get_primary_mut() {
+        Some(window) => window,
+        None => return,
+    };
+    for (text, visible) in overlays.iter() {
+        if asset_server.get_load_state(&text.sections[0].style.font) != LoadState::Failed {
+            continue;
+        }
+        let original = original_title.get_or_insert_with(|| {
+            warn!(
+                "Can't load the font {}, the statistics are shown in the window title",
+                settings.stats.font
+            );
+            window.title().to_string()
+        });
+        let title = if visible.is_visible {
+            format!(
+                "{} | {}",
+                original,
+                text.sections[0].value.replace('\n', " | ")
+            )
+        } else {
+            original.clone()
+        };
+        if window.title() != title {
+            window.set_title(title);
+        }
+    }
+}
+
+/// Send a ping to the server. The answer is handled with the other handshake messages.
+fn ping(
+    mut pinged_at: Local<f64>,
+    time: Res<Time>,
+    handshake: Res<HandshakeStatus>,
+    mut net: ResMut<NetworkResource>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    let now = time.seconds_since_startup();
+    // The server only answers pings from accepted clients
+    if now - *pinged_at < PERIOD || !matches!(*handshake, HandshakeStatus::Accepted) {
+        return;
+    }
+    *pinged_at = now;
+    let handles: Vec<ConnectionHandle> = net.connections.keys().copied().collect();
+    let mut network = GameNetwork::client(&mut *net, &mut *received, &mut *stats);
+    for handle in handles {
+        if network
+            .send_handshake(handle, HandshakeMessage::Ping { sent_at: now })
+            .is_some()
+        {
+            warn!("Can't send ping to server");
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collect(
+    mut totals: Local<Totals>,
+    mut csv: Local<CsvLog>,
+    time: Res<Time>,
+    net: Res<NetworkResource>,
+    counted: Res<NetworkStats>,
+    state_text: Res<StateText>,
+    settings: Res<Settings>,
+    mut net_stats: ResMut<NetStats>,
+    mut overlays: Query<&mut Text, With<StatsOverlay>>,
+) {
+    let now = time.seconds_since_startup();
+    let seconds = now - totals.collected_at;
+    if seconds < PERIOD {
+        return;
+    }
+    let per_second = |now: u64, before: u64| now.saturating_sub(before) as f64 / seconds;
+
+    let (bytes_sent, bytes_received) = net
+        .connections
+        .values()
+        .map(|connection| connection.stats())
+        .fold((0, 0), |(sent, received), packets| {
+            (sent + packets.bytes_tx, received + packets.bytes_rx)
+        });
+    let total = counted.total();
+    let channels = [&total.commands, &total.snapshots, &total.handshake];
+    let mut channel_bytes = [(0, 0); 3];
+    for (bytes, channel) in channel_bytes.iter_mut().zip(channels.iter()) {
+        *bytes = (channel.bytes_sent, channel.bytes_received);
+    }
+    let current = Totals {
+        collected_at: now,
+        bytes_sent,
+        bytes_received,
+        commands_sent: total.commands.messages_sent,
+        channel_bytes,
+        snapshots_received: total.snapshots.messages_received,
+        rollbacks: stats::SNAPSHOTS_APPLIED.get(),
+        steps: stats::STEPS.get(),
+    };
+    let rates = |index: usize| ChannelRates {
+        sent: per_second(
+            current.channel_bytes[index].0,
+            totals.channel_bytes[index].0,
+        ),
+        received: per_second(
+            current.channel_bytes[index].1,
+            totals.channel_bytes[index].1,
+        ),
+    };
+    net_stats.bytes_sent = per_second(current.bytes_sent as u64, totals.bytes_sent as u64);
+    net_stats.bytes_received =
+        per_second(current.bytes_received as u64, totals.bytes_received as u64);
+    net_stats.commands_sent = per_second(current.commands_sent, totals.commands_sent);
+    net_stats.commands = rates(0);
+    net_stats.snapshots_received =
+        per_second(current.snapshots_received, totals.snapshots_received);
+    net_stats.snapshots = rates(1);
+    net_stats.handshake = rates(2);
+    let counted = [net_stats.commands, net_stats.snapshots, net_stats.handshake];
+    net_stats.clock_sync_and_overhead = ChannelRates {
+        sent: (net_stats.bytes_sent - counted.iter().map(|rates| rates.sent).sum::<f64>()).max(0.0),
+        received: (net_stats.bytes_received
+            - counted.iter().map(|rates| rates.received).sum::<f64>())
+        .max(0.0),
+    };
+    net_stats.rollbacks = per_second(current.rollbacks, totals.rollbacks);
+    net_stats.steps = per_second(current.steps, totals.steps);
+    net_stats.round_trip_time = total.round_trip_time;
+    net_stats.clock_offset = total.clock_offset;
+    *totals = current;
+
+    for mut text in overlays.iter_mut() {
+        text.sections[0].value = format!("{}\n{}", state_text.0, overlay_text(&net_stats));
+    }
+
+    if let Some(path) = &settings.stats.csv {
+        if let CsvLog::NotOpened = *csv {
+            *csv = open_csv(path);
+        }
+        if let CsvLog::Open(file) = &mut *csv {
+            let row = format!(
+                "{:.3},{},{}\n",
+                now,
+                csv_field(&state_text.0),
+                csv_row(&net_stats)
+            );
+            if let Err(error) = file.write_all(row.as_bytes()) {
+                error!("Can't write statistics to {}: {}", path.display(), error);
+                *csv = CsvLog::Failed;
+            }
+        }
+    }
+}
+
+fn overlay_text(stats: &NetStats) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    format!(
+        "sent {:.0} B/s, received {:.0} B/s\n\
+         commands {:.1}/s, sent {:.0} B/s, received {:.0} B/s\n\
+         snapshots {:.1}/s, received {:.0} B/s\n\
+         handshake sent {:.0} B/s, received {:.0} B/s\n\
+         clock sync and overhead sent {:.0} B/s, received {:.0} B/s\n\
+         round trip {} ms, clock offset {} ms\n\
+         rollbacks {:.1}/s, steps {:.0}/s",
+        stats.bytes_sent,
+        stats.bytes_received,
+        stats.commands_sent,
+        stats.commands.sent,
+        stats.commands.received,
+        stats.snapshots_received,
+        stats.snapshots.received,
+        stats.handshake.sent,
+        stats.handshake.received,
+        stats.clock_sync_and_overhead.sent,
+        stats.clock_sync_and_overhead.received,
+        optional(
+            stats
+                .round_trip_time
+                .map(|seconds| format!("{:.0}", seconds * 1000.0))
+        ),
+        optional(
+            stats
+                .clock_offset
+                .map(|seconds| format!("{:.0}", seconds * 1000.0))
+        ),
+        stats.rollbacks,
+        stats.steps,
+    )
+}
+
+const CSV_HEADER: &str = "time,stage,bytes_sent,bytes_received,commands_sent,command_bytes_sent,\
+command_bytes_received,snapshots_received,snapshot_bytes_received,handshake_bytes_sent,\
+handshake_bytes_received,clock_sync_and_overhead_bytes_sent,clock_sync_and_overhead_bytes_received,\
+round_trip_time,clock_offset,rollbacks,steps\n";
+
+fn csv_row(stats: &NetStats) -> String {
+    format!(
+        "{:.0},{:.0},{:.1},{:.0},{:.0},{:.1},{:.0},{:.0},{:.0},{:.0},{:.0},{},{},{:.1},{:.0}",
+        stats.bytes_sent,
+        stats.bytes_received,
+        stats.commands_sent,
+        stats.commands.sent,
+        stats.commands.received,
+        stats.snapshots_received,
+        stats.snapshots.received,
+        stats.handshake.sent,
+        stats.handshake.received,
+        stats.clock_sync_and_overhead.sent,
+        stats.clock_sync_and_overhead.received,
+        stats
+            .round_trip_time
+            .map(|seconds| format!("{:.4}", seconds))
+            .unwrap_or_default(),
+        stats
+            .clock_offset
+            .map(|seconds| format!("{:.4}", seconds))
+            .unwrap_or_default(),
+        stats.rollbacks,
+        stats.steps,
+    )
+}
+
+/// Quote a text field as RFC 4180 asks: in double quotes, with inner double quotes doubled.
+fn csv_field(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// Open the CSV file for appending, and write the header if the file is new.
+fn open_csv(path: &Path) -> CsvLog {
+    let mut file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(error) => {
+            error!("Can't open {}: {}", path.display(), error);
+            return CsvLog::Failed;
+        }
+    };
+    let is_new = file
+        .metadata()
+        .map(|metadata| metadata.len() == 0)
+        .unwrap_or(false);
+    if is_new {
+        if let Err(error) = file.write_all(CSV_HEADER.as_bytes()) {
+            error!("Can't write {}: {}", path.display(), error);
+            return CsvLog::Failed;
+        }
+    }
+    info!("Writing statistics to {}", path.display());
+    CsvLog::Open(file)
+}
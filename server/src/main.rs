@@ -1,69 +1,193 @@
+mod metrics;
+
+use metrics::{Metrics, MetricsPlugin};
 use orbgame_shared::{
+    arena::Arena,
     bevy::{self, app::ScheduleRunnerSettings, prelude::*},
+    config::Settings,
     crystalorb_bevy_networking_turbulence::{
-        bevy_networking_turbulence::{
-            self, MessageChannelMode, MessageChannelSettings, NetworkResource,
-            ReliableChannelSettings,
-        },
+        bevy_networking_turbulence::{self, ConnectionHandle, NetworkResource, NetworkingPlugin},
         crystalorb::server::Server,
-        CommandChannelSettings, CrystalOrbServerPlugin, WrappedNetworkResource,
     },
-    game::{GameCommand, GameWorld},
-    SERVER_PORT,
+    game::{codec::SentSnapshots, GameCommand, GameWorld},
+    network::{connection::GameNetwork, Fingerprint, HandshakeMessage, NetworkSettingsPlugin},
+    rules::GameRules,
+    stats::{ChannelCounts, ConnectionStats, NetworkStats},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
-use std::{net::SocketAddr, time::Duration};
+
+/// How long to wait after rejecting a client before disconnecting it,
+/// so the rejection message has time to arrive.
+const REJECT_DISCONNECT_DELAY: f64 = 1.0;
+
+/// How long a client may take to send hello after connecting.
+const HELLO_TIMEOUT: f64 = 5.0;
+
+/// How often to check whether the configuration file has changed.
+const RULES_RELOAD_PERIOD: f64 = 2.0;
+
+/// How often to ping the accepted clients, to log their round trip times.
+const PING_PERIOD: f64 = 1.0;
+
+/// Handshake state of the connected clients.
+#[derive(Default)]
+struct Handshakes {
+    /// Clients that have not sent hello yet, and when they connected.
+    pending: HashMap<ConnectionHandle, f64>,
+    /// Clients that have been accepted, and have been given players or are spectating.
+    accepted: HashSet<ConnectionHandle>,
+    /// Clients that have been rejected, and when to disconnect them.
+    rejected: Vec<(ConnectionHandle, f64)>,
+}
+
+/// The arenas the server plays, in order.
+struct ArenaRotation(Vec<Arena>);
+
+/// Readings at the last statistics log, to log the rates since then.
+#[derive(Default)]
+struct StatsLog {
+    logged_at: f64,
+    /// Bytes sent and received on each connection, and its counted messages.
+    connections: HashMap<ConnectionHandle, (usize, usize, ConnectionStats)>,
+}
+
+/// Rates of one connection or of all of them since the last statistics log, per second.
+#[derive(Default)]
+struct StatsRates {
+    /// Bytes on the connection, including the channels' overhead.
+    bytes_sent: f64,
+    bytes_received: f64,
+    commands_received: f64,
+    /// Commands that the client may not issue, which the server dropped.
+    commands_rejected: u64,
+    snapshots_sent: f64,
+    /// Bytes sent and received on the command, snapshot and handshake channels.
+    channels: [(f64, f64); 3],
+}
+
+/// Where the rules come from, so they can be reloaded.
+struct RulesFile {
+    /// The configuration file, if one was given.
+    path: Option<PathBuf>,
+    /// When the file was last modified, as of the last check.
+    modified: Option<SystemTime>,
+    /// When the file was last checked, in seconds since startup.
+    checked_at: f64,
+    /// The rules that were last loaded.
+    rules: GameRules,
+}
 
 fn main() {
     println!("Server starting");
+    let settings = Settings::from_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let rules_file = RulesFile {
+        modified: settings.path.as_ref().and_then(|path| modified(path)),
+        path: settings.path.clone(),
+        checked_at: 0.0,
+        rules: settings.rules.clone(),
+    };
+    let arenas = settings.load_arenas().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
     App::build()
-        // You can optionally override some message channel settings
-        // There is `CommandChannelSettings`, `SnapshotChannelSettings`, and `ClockSyncChannelSettings`
-        // Make sure you apply the same settings for both client and server.
-        .insert_resource(CommandChannelSettings(MessageChannelSettings {
-            channel: 0,
-            channel_mode: MessageChannelMode::Compressed {
-                reliability_settings: ReliableChannelSettings {
-                    bandwidth: 4096,
-                    recv_window_size: 1024,
-                    send_window_size: 1024,
-                    burst_bandwidth: 1024,
-                    init_send: 512,
-                    wakeup_time: Duration::from_millis(100),
-                    initial_rtt: Duration::from_millis(200),
-                    max_rtt: Duration::from_secs(2),
-                    rtt_update_factor: 0.1,
-                    rtt_resend_factor: 1.5,
-                },
-                max_chunk_len: 1024,
-            },
-            message_buffer_size: 64,
-            packet_buffer_size: 64,
-        }))
+        .add_plugin(NetworkSettingsPlugin::new(&settings))
         .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
-            1.0 / 60.0,
+            settings.timestep,
         )))
         .add_plugins(MinimalPlugins)
-        .add_plugin(CrystalOrbServerPlugin::<GameWorld>::new(
-            orbgame_shared::crystal_orb_config(),
-        ))
+        .add_plugin(NetworkingPlugin::default())
+        .insert_resource(Server::<GameWorld>::new(settings.crystal_orb_config(), 0.0))
+        .insert_resource(SentSnapshots::new(settings.snapshot_precision))
+        .init_resource::<NetworkStats>()
+        .insert_resource(settings)
+        .insert_resource(ArenaRotation(arenas))
+        .insert_resource(rules_file)
+        .init_resource::<Handshakes>()
         .add_plugin(bevy::log::LogPlugin)
+        .add_plugin(MetricsPlugin)
         .add_startup_system(server_setup.system())
+        .add_startup_system(start_match.system())
+        .add_system(update_server.system())
         .add_system(handle_events.system())
+        .add_system(handle_handshakes.system())
+        .add_system(ping_clients.system())
+        .add_system(log_dropped_commands.system())
+        .add_system(log_stats.system())
+        .add_system(reload_rules.system())
         .run();
 }
 
-fn server_setup(mut net: ResMut<NetworkResource>) {
-    let ip_address =
-        bevy_networking_turbulence::find_my_ip_address().expect("can't find ip address");
-    let socket_address = SocketAddr::new(ip_address, SERVER_PORT);
+fn server_setup(mut net: ResMut<NetworkResource>, settings: Res<Settings>) {
+    let ip_address = settings.address.unwrap_or_else(|| {
+        bevy_networking_turbulence::find_my_ip_address().expect("can't find ip address")
+    });
+    let socket_address = SocketAddr::new(ip_address, settings.port);
     info!("Starting server on address {}", socket_address);
     net.listen(socket_address, None, None);
 }
 
+/// Run the server's part of the netcode: simulate, and exchange messages with the clients.
+fn update_server(
+    mut server: ResMut<Server<GameWorld>>,
+    mut net: ResMut<NetworkResource>,
+    mut sent: ResMut<SentSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    metrics: Res<Arc<Metrics>>,
+    time: Res<Time>,
+) {
+    let started_at = Instant::now();
+    server.update(
+        time.delta_seconds_f64(),
+        time.seconds_since_startup(),
+        &mut GameNetwork::server(&mut *net, &mut *sent, &mut *stats),
+    );
+    metrics.observe_tick(started_at.elapsed().as_secs_f64());
+}
+
+fn start_match(
+    mut server: ResMut<Server<GameWorld>>,
+    mut net: ResMut<NetworkResource>,
+    mut sent: ResMut<SentSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    settings: Res<Settings>,
+    rotation: Res<ArenaRotation>,
+) {
+    // Logged here rather than when loading, because logging only starts with the app
+    for arena in rotation.0.iter() {
+        info!("Using arena {}", arena.name);
+    }
+    let command = GameCommand::ConfigureMatch {
+        settings: settings.match_settings.clone(),
+        world: settings.world_settings(),
+        rotation: rotation.0.clone(),
+        rules: settings.rules.clone(),
+    };
+    server.issue_command(
+        command,
+        &mut GameNetwork::server(&mut *net, &mut *sent, &mut *stats),
+    );
+}
+
 fn handle_events(
     mut event_reader: EventReader<bevy_networking_turbulence::NetworkEvent>,
     mut server: ResMut<Server<GameWorld>>,
     mut net: ResMut<NetworkResource>,
+    mut sent: ResMut<SentSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    mut handshakes: ResMut<Handshakes>,
+    time: Res<Time>,
 ) {
     for event in event_reader.iter() {
         debug!("Got event: {:?}", event);
@@ -75,18 +199,364 @@ fn handle_events(
                     connection.remote_address(),
                     handle,
                 );
-                let command = GameCommand::SpawnPlayer {
-                    client_handle: *handle,
-                };
-                server.issue_command(command, &mut WrappedNetworkResource(&mut *net));
+                // The player is spawned when the client has sent hello, see handle_handshakes
+                handshakes
+                    .pending
+                    .insert(*handle, time.seconds_since_startup());
             }
             bevy_networking_turbulence::NetworkEvent::Disconnected(handle) => {
                 info!("Client disconnected: {:?}", handle);
+                handshakes.pending.remove(handle);
+                sent.forget_client(*handle);
+                stats.forget_connection(*handle);
+                if handshakes.accepted.remove(handle) {
+                    let command = GameCommand::DespawnPlayer {
+                        client_handle: *handle,
+                    };
+                    server.issue_command(
+                        command,
+                        &mut GameNetwork::server(&mut *net, &mut *sent, &mut *stats),
+                    );
+                }
             }
             bevy_networking_turbulence::NetworkEvent::Packet(_, _) => {}
             bevy_networking_turbulence::NetworkEvent::Error(handle, error) => {
                 error!("Got error on handle {}: {:?}", handle, error);
+                stats.record_error(*handle);
+            }
+        }
+    }
+}
+
+/// Answer handshake messages from clients. Spawn players for compatible clients,
+/// and disconnect clients that are not compatible.
+/// Accepted clients can switch between playing and spectating.
+fn handle_handshakes(
+    mut net: ResMut<NetworkResource>,
+    mut server: ResMut<Server<GameWorld>>,
+    mut sent: ResMut<SentSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    mut handshakes: ResMut<Handshakes>,
+    fingerprint: Res<Fingerprint>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    let mut replies = Vec::new();
+    // Accepted clients, and how many players they want. Zero means spectating.
+    let mut spawns = Vec::new();
+    // The server's display state is its world as of the last step, not an interpolation
+    let mut free_slots = server.display_state().inner().free_player_slots;
+    let messages = GameNetwork::server(&mut *net, &mut *sent, &mut *stats).recv_handshakes();
+    for (handle, message) in messages {
+        if handshakes.accepted.contains(&handle) {
+            match message {
+                // All players of a client join together, or none of them
+                HandshakeMessage::Join { local_players }
+                    if local_players.max(1) as usize <= free_slots =>
+                {
+                    info!("Client {} joins with {} players", handle, local_players);
+                    free_slots -= local_players.max(1) as usize;
+                    spawns.push((handle, local_players.max(1)));
+                }
+                HandshakeMessage::Join { .. } => {
+                    info!("Client {} can't join, there is no room", handle);
+                }
+                HandshakeMessage::Spectate => spawns.push((handle, 0)),
+                HandshakeMessage::Ping { sent_at } => {
+                    replies.push((handle, HandshakeMessage::Pong { sent_at }))
+                }
+                HandshakeMessage::Pong { sent_at } => {
+                    stats.record_round_trip_time(handle, now - sent_at)
+                }
+                HandshakeMessage::Acknowledge { snapshots } => sent.acknowledge(handle, &snapshots),
+                _ => warn!("Ignoring handshake from accepted client {}", handle),
+            }
+            continue;
+        }
+        handshakes.pending.remove(&handle);
+        match message.check_hello(*fingerprint) {
+            Ok(local_players) => {
+                info!(
+                    "Client {} is compatible, and wants {} players",
+                    handle, local_players
+                );
+                // Clients spectate until they join, if the match has no room for all their players
+                let local_players = if local_players as usize > free_slots {
+                    0
+                } else {
+                    local_players
+                };
+                free_slots = free_slots.saturating_sub(local_players as usize);
+                spawns.push((handle, local_players));
+                handshakes.accepted.insert(handle);
+                replies.push((handle, HandshakeMessage::Welcome));
+            }
+            Err(reason) => {
+                warn!("Rejecting client {}: {}", handle, reason);
+                replies.push((handle, HandshakeMessage::Rejected { reason }));
+                handshakes
+                    .rejected
+                    .push((handle, now + REJECT_DISCONNECT_DELAY));
+            }
+        }
+    }
+
+    let timed_out: Vec<ConnectionHandle> = handshakes
+        .pending
+        .iter()
+        .filter(|(_, connected_at)| now - **connected_at > HELLO_TIMEOUT)
+        .map(|(handle, _)| *handle)
+        .collect();
+    for handle in timed_out {
+        warn!("Rejecting client {}: no hello received", handle);
+        handshakes.pending.remove(&handle);
+        let reason = "No hello received, the client may be too old".to_string();
+        replies.push((handle, HandshakeMessage::Rejected { reason }));
+        handshakes
+            .rejected
+            .push((handle, now + REJECT_DISCONNECT_DELAY));
+    }
+
+    let mut network = GameNetwork::server(&mut *net, &mut *sent, &mut *stats);
+    for (handle, message) in replies {
+        if network.send_handshake(handle, message).is_some() {
+            error!("Can't send handshake to client {}", handle);
+        }
+    }
+    for (handle, local_players) in spawns {
+        let command = if local_players == 0 {
+            GameCommand::Spectate {
+                client_handle: handle,
+            }
+        } else {
+            GameCommand::SpawnPlayer {
+                client_handle: handle,
+                local_players,
             }
+        };
+        server.issue_command(command, &mut network);
+    }
+
+    let (due, pending): (Vec<_>, Vec<_>) = handshakes
+        .rejected
+        .drain(..)
+        .partition(|(_, at)| *at <= now);
+    handshakes.rejected = pending;
+    for (handle, _) in due {
+        info!("Disconnecting rejected client {}", handle);
+        // Turbulence sends no event for connections that are removed
+        net.connections.remove(&handle);
+        sent.forget_client(handle);
+        stats.forget_connection(handle);
+    }
+}
+
+/// Ping the accepted clients now and then, to log their round trip times.
+/// The answers are handled with the other handshake messages.
+fn ping_clients(
+    mut pinged_at: Local<f64>,
+    handshakes: Res<Handshakes>,
+    mut net: ResMut<NetworkResource>,
+    mut sent: ResMut<SentSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    if now - *pinged_at < PING_PERIOD {
+        return;
+    }
+    *pinged_at = now;
+    let mut network = GameNetwork::server(&mut *net, &mut *sent, &mut *stats);
+    for handle in handshakes.accepted.iter() {
+        let ping = HandshakeMessage::Ping { sent_at: now };
+        if network.send_handshake(*handle, ping).is_some() {
+            warn!("Can't send ping to client {}", handle);
+        }
+    }
+}
+
+/// Log when the game world has dropped commands it could not apply.
+fn log_dropped_commands(mut previous: Local<u32>, server: Res<Server<GameWorld>>) {
+    let dropped_commands = server.display_state().inner().dropped_commands;
+    if dropped_commands != *previous {
+        warn!(
+            "Dropped {} commands ({} in total)",
+            dropped_commands.wrapping_sub(*previous),
+            dropped_commands
+        );
+        *previous = dropped_commands;
+    }
+}
+
+/// Log the netcode statistics of each connection, and of all of them, now and then.
+fn log_stats(
+    mut log: Local<StatsLog>,
+    net: Res<NetworkResource>,
+    stats: Res<NetworkStats>,
+    settings: Res<Settings>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    let seconds = now - log.logged_at;
+    if seconds < settings.stats.log_period {
+        return;
+    }
+    log.logged_at = now;
+
+    let mut total = StatsRates::default();
+    for (handle, connection) in net.connections.iter() {
+        let packets = connection.stats();
+        let counted = stats.connection(*handle).cloned().unwrap_or_default();
+        let (bytes_tx, bytes_rx, before) = log
+            .connections
+            .insert(
+                *handle,
+                (packets.bytes_tx, packets.bytes_rx, counted.clone()),
+            )
+            .unwrap_or_default();
+        let rates = StatsRates::since(
+            (packets.bytes_tx, packets.bytes_rx),
+            (bytes_tx, bytes_rx),
+            &counted,
+            &before,
+            seconds,
+        );
+        let round_trip_time = counted
+            .round_trip_time
+            .map(|seconds| format!("{:.0} ms", seconds * 1000.0))
+            .unwrap_or_else(|| "-".to_string());
+        info!(
+            "Stats of client {}: {}, round trip {}",
+            handle, rates, round_trip_time
+        );
+        total.add(&rates);
+    }
+    log.connections
+        .retain(|handle, _| net.connections.contains_key(handle));
+    info!("Stats: {} clients, {}", net.connections.len(), total);
+}
+
+impl StatsRates {
+    fn since(
+        bytes: (usize, usize),
+        bytes_before: (usize, usize),
+        counted: &ConnectionStats,
+        before: &ConnectionStats,
+        seconds: f64,
+    ) -> Self {
+        let per_second = |now: u64, before: u64| now.saturating_sub(before) as f64 / seconds;
+        let channel = |now: &ChannelCounts, before: &ChannelCounts| {
+            (
+                per_second(now.bytes_sent, before.bytes_sent),
+                per_second(now.bytes_received, before.bytes_received),
+            )
+        };
+        Self {
+            bytes_sent: per_second(bytes.0 as u64, bytes_before.0 as u64),
+            bytes_received: per_second(bytes.1 as u64, bytes_before.1 as u64),
+            commands_received: per_second(
+                counted.commands.messages_received,
+                before.commands.messages_received,
+            ),
+            commands_rejected: counted
+                .commands_rejected
+                .saturating_sub(before.commands_rejected),
+            snapshots_sent: per_second(
+                counted.snapshots.messages_sent,
+                before.snapshots.messages_sent,
+            ),
+            channels: [
+                channel(&counted.commands, &before.commands),
+                channel(&counted.snapshots, &before.snapshots),
+                channel(&counted.handshake, &before.handshake),
+            ],
+        }
+    }
+
+    fn add(&mut self, other: &StatsRates) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.commands_received += other.commands_received;
+        self.commands_rejected += other.commands_rejected;
+        self.snapshots_sent += other.snapshots_sent;
+        for (rates, other) in self.channels.iter_mut().zip(other.channels.iter()) {
+            rates.0 += other.0;
+            rates.1 += other.1;
+        }
+    }
+}
+
+impl Display for StatsRates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [commands, snapshots, handshake] = self.channels;
+        // What the channels leave is crystalorb's clock sync messages and the channels' overhead
+        let uncounted = |bytes: f64, counted: f64| (bytes - counted).max(0.0);
+        write!(
+            f,
+            "sent {:.0} B/s, received {:.0} B/s; commands {:.1}/s received, {} rejected, \
+             sent {:.0} B/s, received {:.0} B/s; snapshots {:.1}/s sent, {:.0} B/s; \
+             handshake sent {:.0} B/s, received {:.0} B/s; \
+             clock sync and overhead sent {:.0} B/s, received {:.0} B/s",
+            self.bytes_sent,
+            self.bytes_received,
+            self.commands_received,
+            self.commands_rejected,
+            commands.0,
+            commands.1,
+            self.snapshots_sent,
+            snapshots.0,
+            handshake.0,
+            handshake.1,
+            uncounted(self.bytes_sent, commands.0 + snapshots.0 + handshake.0),
+            uncounted(self.bytes_received, commands.1 + snapshots.1 + handshake.1),
+        )
+    }
+}
+
+/// Reload the rules when the configuration file has changed.
+/// The world switches to the new rules when the next round starts.
+fn reload_rules(
+    mut rules_file: ResMut<RulesFile>,
+    mut server: ResMut<Server<GameWorld>>,
+    mut net: ResMut<NetworkResource>,
+    mut sent: ResMut<SentSnapshots>,
+    mut stats: ResMut<NetworkStats>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    if now - rules_file.checked_at < RULES_RELOAD_PERIOD {
+        return;
+    }
+    rules_file.checked_at = now;
+    let path = match &rules_file.path {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    let modified = modified(&path);
+    if modified == rules_file.modified {
+        return;
+    }
+    rules_file.modified = modified;
+    let rules = match Settings::load(&path) {
+        Ok(settings) => settings.rules,
+        Err(e) => {
+            warn!("Not reloading rules: {}", e);
+            return;
         }
+    };
+    if rules == rules_file.rules {
+        return;
     }
+    info!("Rules changed, they take effect when the next round starts");
+    rules_file.rules = rules.clone();
+    server.issue_command(
+        GameCommand::UpdateRules(rules),
+        &mut GameNetwork::server(&mut *net, &mut *sent, &mut *stats),
+    );
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
 }
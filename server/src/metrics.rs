@@ -0,0 +1,233 @@
+//! Server metrics in the Prometheus text format, served over HTTP on localhost
+//! when `metrics_port` is set in the statistics settings.
+
+use orbgame_shared::{
+    bevy::prelude::*,
+    config::Settings,
+    crystalorb_bevy_networking_turbulence::{
+        bevy_networking_turbulence::NetworkResource, crystalorb::server::Server,
+    },
+    game::GameWorld,
+    stats::{self, Histogram, NetworkStats},
+};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Upper bounds of the tick duration buckets, in seconds.
+const TICK_BUCKETS: [f64; 9] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.0167, 0.025, 0.05, 0.1];
+
+/// How long to wait for a scraper's request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct MetricsPlugin;
+
+/// The values shared with the HTTP thread. The gauges and counters are copied at the end of
+/// each frame.
+pub struct Metrics {
+    connected_clients: AtomicU64,
+    players: AtomicU64,
+    dropped_commands: AtomicU64,
+    commands_received: AtomicU64,
+    snapshots_sent: AtomicU64,
+    snapshot_bytes_sent: AtomicU64,
+    commands_rejected: AtomicU64,
+    network_errors: AtomicU64,
+    tick_duration: Mutex<Histogram>,
+}
+
+impl Plugin for MetricsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Arc::new(Metrics::default()))
+            .add_startup_system(serve.system())
+            .add_system_to_stage(CoreStage::Last, end_frame.system());
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connected_clients: AtomicU64::new(0),
+            players: AtomicU64::new(0),
+            dropped_commands: AtomicU64::new(0),
+            commands_received: AtomicU64::new(0),
+            snapshots_sent: AtomicU64::new(0),
+            snapshot_bytes_sent: AtomicU64::new(0),
+            commands_rejected: AtomicU64::new(0),
+            network_errors: AtomicU64::new(0),
+            tick_duration: Mutex::new(Histogram::new(TICK_BUCKETS.to_vec())),
+        }
+    }
+}
+
+impl Metrics {
+    /// Record how long a crystalorb update of the server took.
+    pub fn observe_tick(&self, seconds: f64) {
+        self.tick_duration.lock().unwrap().observe(seconds);
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        stats::write_prometheus(
+            &mut text,
+            "orbgame_connected_clients",
+            "gauge",
+            "Connected clients, including clients that have not finished the handshake.",
+            self.connected_clients.load(Ordering::Relaxed) as f64,
+        );
+        stats::write_prometheus(
+            &mut text,
+            "orbgame_players",
+            "gauge",
+            "Players in the game world.",
+            self.players.load(Ordering::Relaxed) as f64,
+        );
+        self.tick_duration.lock().unwrap().write_prometheus(
+            &mut text,
+            "orbgame_tick_duration_seconds",
+            "How long each crystalorb update of the server takes: the world's steps, \
+             and the commands and snapshots it sends.",
+        );
+        let counters = [
+            (
+                "orbgame_commands_received_total",
+                "Commands received from clients.",
+                &self.commands_received,
+            ),
+            (
+                "orbgame_commands_rejected_total",
+                "Commands from clients that the server dropped, because the clients may not \
+                 issue them.",
+                &self.commands_rejected,
+            ),
+            (
+                "orbgame_snapshots_total",
+                "Snapshots sent, once for each client.",
+                &self.snapshots_sent,
+            ),
+            (
+                "orbgame_snapshot_bytes_total",
+                "Bytes of the encoded snapshots, without the channel's overhead.",
+                &self.snapshot_bytes_sent,
+            ),
+            (
+                "orbgame_network_errors_total",
+                "Errors reported by the network connections.",
+                &self.network_errors,
+            ),
+        ];
+        for (name, help, counter) in counters.iter() {
+            stats::write_prometheus(
+                &mut text,
+                name,
+                "counter",
+                help,
+                counter.load(Ordering::Relaxed) as f64,
+            );
+        }
+        stats::write_prometheus(
+            &mut text,
+            "orbgame_commands_dropped_total",
+            "counter",
+            "Commands that the game world could not apply.",
+            self.dropped_commands.load(Ordering::Relaxed) as f64,
+        );
+        text
+    }
+}
+
+/// Start the HTTP thread if a metrics port is set.
+fn serve(metrics: Res<Arc<Metrics>>, settings: Res<Settings>) {
+    let port = match settings.stats.metrics_port {
+        Some(port) => port,
+        None => return,
+    };
+    let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Can't serve metrics on port {}: {}", port, error);
+            return;
+        }
+    };
+    info!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+    let metrics = Arc::clone(&metrics);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(error) = respond(stream, &metrics) {
+                        debug!("Can't answer metrics request: {}", error);
+                    }
+                }
+                Err(error) => warn!("Can't accept metrics connection: {}", error),
+            }
+        }
+    });
+}
+
+/// Answer one request, and close the connection.
+fn respond(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, the request has no body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    reader.into_inner().write_all(response.as_bytes())
+}
+
+fn end_frame(
+    metrics: Res<Arc<Metrics>>,
+    net: Res<NetworkResource>,
+    server: Res<Server<GameWorld>>,
+    stats: Res<NetworkStats>,
+) {
+    metrics
+        .connected_clients
+        .store(net.connections.len() as u64, Ordering::Relaxed);
+    let display_state = server.display_state();
+    metrics.players.store(
+        display_state.inner().player_positions.len() as u64,
+        Ordering::Relaxed,
+    );
+    metrics.dropped_commands.store(
+        display_state.inner().dropped_commands as u64,
+        Ordering::Relaxed,
+    );
+    let total = stats.total();
+    let counters = [
+        (&metrics.commands_received, total.commands.messages_received),
+        (&metrics.commands_rejected, total.commands_rejected),
+        (&metrics.snapshots_sent, total.snapshots.messages_sent),
+        (&metrics.snapshot_bytes_sent, total.snapshots.bytes_sent),
+        (&metrics.network_errors, total.errors),
+    ];
+    for (metric, value) in counters.iter() {
+        metric.store(*value, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,152 @@
+//! Compares the size of the players in snapshots as serde wrote them before the codec,
+//! quantized, and delta-encoded. The bandwidth is of the snapshot messages as they are sent to a
+//! client whose acknowledgements arrive a few snapshots late.
+//! Run with `cargo bench --package orbgame_shared`.
+
+use crystalorb::{
+    fixed_timestepper::Stepper,
+    timestamp::{Timestamp, Timestamped},
+    world::World,
+};
+use orbgame_shared::{
+    arena::Arena,
+    crystal_orb_config,
+    game::{
+        codec::{self, ReceivedSnapshots, SentSnapshots, SnapshotPrecision},
+        input::FULL_STEERING,
+        GameCommand, GameWorld, PlayerInput,
+    },
+    match_state::MatchSettings,
+};
+use std::{collections::VecDeque, time::Instant};
+
+/// The bandwidth budget of the network channels, in bytes per second.
+const BANDWIDTH_BUDGET: f64 = 4096.0;
+
+/// How often each encoding is timed.
+const ITERATIONS: u32 = 1000;
+
+/// How many snapshots are sent to measure the bandwidth.
+const SENDS: usize = 60;
+
+/// How many snapshots late the client's acknowledgements arrive, about 100 ms at 30 snapshots
+/// per second.
+const ACK_LAG: usize = 3;
+
+fn world_with_players(players: u8) -> GameWorld {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::ConfigureMatch {
+        settings: MatchSettings {
+            max_players: players,
+            ..Default::default()
+        },
+        world: Default::default(),
+        rotation: vec![Arena::default()],
+        rules: Default::default(),
+    });
+    for client_handle in 0..players as u32 {
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle,
+            local_players: 1,
+        });
+        let steering = if client_handle % 2 == 0 {
+            FULL_STEERING
+        } else {
+            -FULL_STEERING
+        };
+        world.apply_command(&GameCommand::SetInput(
+            client_handle,
+            0,
+            PlayerInput {
+                steering,
+                jump: client_handle % 3 == 0,
+                ..Default::default()
+            },
+        ));
+    }
+    // Let the players spread out and move
+    for _ in 0..120 {
+        world.step();
+    }
+    world
+}
+
+/// Encoded size in bytes, and microseconds per encoding.
+fn measure(encode: impl Fn() -> Vec<u8>) -> (usize, f64) {
+    let size = encode().len();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        encode();
+    }
+    let micros = start.elapsed().as_secs_f64() * 1e6 / ITERATIONS as f64;
+    (size, micros)
+}
+
+/// Average size in bytes of the snapshot messages sent to a client while the world keeps
+/// moving, as they are serialized on the snapshot channel.
+fn wire_size(world: &mut GameWorld, steps_per_snapshot: usize) -> f64 {
+    let mut sent = SentSnapshots::new(SnapshotPrecision::default());
+    let mut received = ReceivedSnapshots::default();
+    let mut acknowledgements = VecDeque::new();
+    let mut timestamp = Timestamp::default();
+    let mut total = 0;
+    for _ in 0..SENDS {
+        for _ in 0..steps_per_snapshot {
+            world.step();
+            timestamp.increment();
+        }
+        let message = sent.encode(0, &Timestamped::new(world.snapshot(), timestamp));
+        total += bincode::serialized_size(&message).unwrap();
+        received
+            .decode(&message)
+            .expect("snapshot message does not decode");
+        acknowledgements.push_back(received.take_acknowledgements());
+        if acknowledgements.len() > ACK_LAG {
+            sent.acknowledge(0, &acknowledgements.pop_front().unwrap());
+        }
+    }
+    total as f64 / SENDS as f64
+}
+
+fn main() {
+    let snapshots_per_second = 1.0 / crystal_orb_config().snapshot_send_period;
+    let steps_per_snapshot =
+        (1.0 / snapshots_per_second / orbgame_shared::TIMESTEP).round() as usize;
+    println!(
+        "{:>7} {:>16} {:>16} {:>16} {:>8}",
+        "players", "serde", "quantized", "delta", "bytes/s"
+    );
+    for players in [2, 16, 64].iter() {
+        let mut world = world_with_players(*players);
+        let baseline = world.snapshot();
+        for _ in 0..steps_per_snapshot {
+            world.step();
+        }
+        let snapshot = world.snapshot();
+
+        let precision = SnapshotPrecision::default();
+        let serde = measure(|| bincode::serialize(snapshot.players()).unwrap());
+        let quantized = measure(|| codec::encode_players(snapshot.players(), None, precision));
+        let delta = measure(|| {
+            codec::encode_players(snapshot.players(), Some(baseline.players()), precision)
+        });
+
+        let bytes_per_second = wire_size(&mut world, steps_per_snapshot) * snapshots_per_second;
+        println!(
+            "{:>7} {:>7} B {:>4.0} us {:>7} B {:>4.0} us {:>7} B {:>4.0} us {:>8.0}{}",
+            players,
+            serde.0,
+            serde.1,
+            quantized.0,
+            quantized.1,
+            delta.0,
+            delta.1,
+            bytes_per_second,
+            if bytes_per_second > BANDWIDTH_BUDGET {
+                " over budget"
+            } else {
+                ""
+            }
+        );
+    }
+}
@@ -0,0 +1,418 @@
+//! Arena definitions: the static shapes players bounce around in, where they spawn,
+//! and the bounds of the playing area.
+//!
+//! Arenas are loaded from TOML files, see the `arenas` directory.
+
+use crate::{config::ConfigError, network::fnv1a};
+use rapier2d::{na::DVector, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    fs,
+    path::Path,
+};
+
+/// Identifies an arena by a hash of its contents.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ArenaId(pub u64);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Arena {
+    pub name: String,
+    /// Players that leave these bounds are moved back to a spawn point.
+    pub bounds: Bounds,
+    /// Where players spawn. Player ids are assigned to spawn points in order.
+    pub spawn_points: Vec<[Real; 2]>,
+    #[serde(default)]
+    pub shapes: Vec<StaticShape>,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+    #[serde(default)]
+    pub pickups: Vec<PickupSpawn>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Bounds {
+    pub min: [Real; 2],
+    pub max: [Real; 2],
+}
+
+/// A shape that does not move.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StaticShape {
+    #[serde(flatten)]
+    pub shape: Shape,
+    #[serde(default)]
+    pub position: [Real; 2],
+    /// Rotation in radians.
+    #[serde(default)]
+    pub rotation: Real,
+    #[serde(default = "default_restitution")]
+    pub restitution: Real,
+    #[serde(default = "default_friction")]
+    pub friction: Real,
+}
+
+/// An area that players pass through, which the game reacts to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Zone {
+    pub kind: ZoneKind,
+    #[serde(flatten)]
+    pub shape: Shape,
+    #[serde(default)]
+    pub position: [Real; 2],
+    /// Rotation in radians.
+    #[serde(default)]
+    pub rotation: Real,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneKind {
+    /// The hill in king of the hill.
+    Hill,
+    /// Players that touch a hazard are knocked out, and respawn.
+    Hazard,
+    /// Players score a point for reaching a goal, and respawn.
+    Goal,
+    /// Players that have reached a checkpoint respawn there.
+    Checkpoint,
+}
+
+/// A collectible that appears at a fixed place, and comes back some time after being picked up.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PickupSpawn {
+    pub kind: PickupKind,
+    pub position: [Real; 2],
+    #[serde(default = "default_respawn_seconds")]
+    pub respawn_seconds: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PickupKind {
+    /// Stronger steering for a while.
+    SpeedBoost,
+    /// One more jump in the air.
+    ExtraJump,
+    /// Heavier for a while, to push others around.
+    HeavierMass,
+}
+
+/// The handles of what [`Arena::build`] inserted into the physics world.
+#[derive(Debug, Default)]
+pub struct ArenaHandles {
+    /// The static bodies. Removing them also removes their colliders.
+    pub bodies: Vec<RigidBodyHandle>,
+    /// The sensor colliders of the arena's zones, in the order of [`Arena::zones`].
+    pub zones: Vec<ColliderHandle>,
+    /// The bodies that the pickups are attached to, in the order of [`Arena::pickups`].
+    /// The pickups' colliders are added by the world, when they are available.
+    pub pickups: Vec<RigidBodyHandle>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Shape {
+    Cuboid {
+        half_extents: [Real; 2],
+    },
+    Ball {
+        radius: Real,
+    },
+    /// Connected line segments.
+    Polyline {
+        points: Vec<[Real; 2]>,
+    },
+    /// Heights at evenly spaced points along the x axis.
+    /// The heightfield is `scale[0]` wide and centered on the shape's position,
+    /// and the heights are multiplied by `scale[1]`.
+    Heightfield {
+        heights: Vec<Real>,
+        scale: [Real; 2],
+    },
+}
+
+fn default_restitution() -> Real {
+    0.5
+}
+
+fn default_friction() -> Real {
+    0.5
+}
+
+fn default_respawn_seconds() -> f64 {
+    10.0
+}
+
+impl Display for ArenaId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl Default for Arena {
+    /// A 180×100 box.
+    fn default() -> Self {
+        let wall = |half_extents: [Real; 2], position: [Real; 2]| StaticShape {
+            shape: Shape::Cuboid { half_extents },
+            position,
+            rotation: 0.0,
+            restitution: default_restitution(),
+            friction: default_friction(),
+        };
+        Self {
+            name: "Box".to_string(),
+            bounds: Bounds {
+                min: [0.0, 0.0],
+                max: [180.0, 100.0],
+            },
+            spawn_points: vec![[10.0, 80.0]],
+            shapes: vec![
+                wall([1.0, 100.0], [0.0, 0.0]),
+                wall([1.0, 100.0], [180.0, 0.0]),
+                wall([180.0, 1.0], [0.0, 0.0]),
+                wall([180.0, 1.0], [0.0, 100.0]),
+            ],
+            zones: Vec::new(),
+            pickups: Vec::new(),
+        }
+    }
+}
+
+impl Arena {
+    pub fn id(&self) -> ArenaId {
+        ArenaId(fnv1a(format!("{:?}", self).as_bytes()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let arena: Self =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        arena.validate()?;
+        Ok(arena)
+    }
+
+    /// Check for values that rapier can't build, or that make the game misbehave.
+    /// Arenas are sent to clients, so a bad one must be rejected before it is built anywhere.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |problem: String| {
+            Err(ConfigError::Invalid(format!(
+                "arena {}: {}",
+                self.name, problem
+            )))
+        };
+        let bounds = &self.bounds;
+        if !(finite(&bounds.min) && finite(&bounds.max))
+            || bounds.min[0] >= bounds.max[0]
+            || bounds.min[1] >= bounds.max[1]
+        {
+            return invalid(format!("bounds are empty: {:?}", bounds));
+        }
+        if self.spawn_points.is_empty() {
+            return invalid("there are no spawn points".to_string());
+        }
+        if let Some(point) = self.spawn_points.iter().find(|point| !finite(*point)) {
+            return invalid(format!("spawn point {:?} is not a number", point));
+        }
+        for (index, shape) in self.shapes.iter().enumerate() {
+            let placed = finite(&shape.position) && shape.rotation.is_finite();
+            let material = shape.restitution.is_finite()
+                && shape.restitution >= 0.0
+                && shape.friction.is_finite()
+                && shape.friction >= 0.0;
+            if !placed || !material {
+                return invalid(format!("shape {} is not placed or has no material", index));
+            }
+            if let Err(problem) = shape.shape.validate() {
+                return invalid(format!("shape {}: {}", index, problem));
+            }
+        }
+        for (index, zone) in self.zones.iter().enumerate() {
+            if !(finite(&zone.position) && zone.rotation.is_finite()) {
+                return invalid(format!("zone {} is not placed", index));
+            }
+            if let Err(problem) = zone.shape.validate() {
+                return invalid(format!("zone {}: {}", index, problem));
+            }
+        }
+        for (index, pickup) in self.pickups.iter().enumerate() {
+            if !finite(&pickup.position)
+                || !(pickup.respawn_seconds.is_finite() && pickup.respawn_seconds >= 0.0)
+            {
+                return invalid(format!("pickup {} is not placed or never respawns", index));
+            }
+        }
+        Ok(())
+    }
+
+    /// Insert the arena's shapes, zones and pickup bodies into the physics world.
+    pub fn build(&self, bodies: &mut RigidBodySet, colliders: &mut ColliderSet) -> ArenaHandles {
+        let mut handles = ArenaHandles::default();
+        for shape in self.shapes.iter() {
+            let body_handle = bodies.insert(
+                RigidBodyBuilder::new_static()
+                    .translation(vector![shape.position[0], shape.position[1]])
+                    .rotation(shape.rotation)
+                    .ccd_enabled(true)
+                    .build(),
+            );
+            colliders.insert_with_parent(
+                shape
+                    .shape
+                    .collider_builder()
+                    .restitution(shape.restitution)
+                    .friction(shape.friction)
+                    .build(),
+                body_handle,
+                bodies,
+            );
+            handles.bodies.push(body_handle);
+        }
+        for zone in self.zones.iter() {
+            let body_handle = bodies.insert(
+                RigidBodyBuilder::new_static()
+                    .translation(vector![zone.position[0], zone.position[1]])
+                    .rotation(zone.rotation)
+                    .build(),
+            );
+            let collider_handle = colliders.insert_with_parent(
+                zone.shape.collider_builder().sensor(true).build(),
+                body_handle,
+                bodies,
+            );
+            handles.bodies.push(body_handle);
+            handles.zones.push(collider_handle);
+        }
+        for pickup in self.pickups.iter() {
+            let body_handle = bodies.insert(
+                RigidBodyBuilder::new_static()
+                    .translation(vector![pickup.position[0], pickup.position[1]])
+                    .build(),
+            );
+            handles.bodies.push(body_handle);
+            handles.pickups.push(body_handle);
+        }
+        handles
+    }
+
+    /// Where a player spawns if it has not reached a checkpoint.
+    pub fn spawn_point(&self, index: usize) -> Vector<Real> {
+        match self.spawn_points.len() {
+            0 => self.bounds.center(),
+            len => {
+                let [x, y] = self.spawn_points[index % len];
+                vector![x, y]
+            }
+        }
+    }
+}
+
+impl Bounds {
+    pub fn center(&self) -> Vector<Real> {
+        vector![
+            (self.min[0] + self.max[0]) / 2.0,
+            (self.min[1] + self.max[1]) / 2.0
+        ]
+    }
+
+    pub fn size(&self) -> Vector<Real> {
+        vector![self.max[0] - self.min[0], self.max[1] - self.min[1]]
+    }
+
+    /// Whether a point is inside the bounds, or at most `margin` outside.
+    pub fn contains(&self, point: &Vector<Real>, margin: Real) -> bool {
+        point.x >= self.min[0] - margin
+            && point.x <= self.max[0] + margin
+            && point.y >= self.min[1] - margin
+            && point.y <= self.max[1] + margin
+    }
+}
+
+impl Shape {
+    /// Check that rapier can build the shape, see [`Arena::validate`].
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Shape::Cuboid { half_extents } => {
+                if !(finite(half_extents) && half_extents[0] > 0.0 && half_extents[1] > 0.0) {
+                    return Err(format!("half extents {:?} are not positive", half_extents));
+                }
+            }
+            Shape::Ball { radius } => {
+                if !(radius.is_finite() && *radius > 0.0) {
+                    return Err(format!("radius {} is not positive", radius));
+                }
+            }
+            Shape::Polyline { points } => {
+                if points.len() < 2 {
+                    return Err(format!("a polyline needs 2 points, not {}", points.len()));
+                }
+                if !points.iter().all(finite) {
+                    return Err("a point is not a number".to_string());
+                }
+            }
+            Shape::Heightfield { heights, scale } => {
+                if heights.len() < 2 {
+                    return Err(format!(
+                        "a heightfield needs 2 heights, not {}",
+                        heights.len()
+                    ));
+                }
+                if !heights.iter().all(|height| height.is_finite()) {
+                    return Err("a height is not a number".to_string());
+                }
+                if !(finite(scale) && scale[0] > 0.0 && scale[1] > 0.0) {
+                    return Err(format!("scale {:?} is not positive", scale));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn collider_builder(&self) -> ColliderBuilder {
+        match self {
+            Shape::Cuboid { half_extents } => {
+                ColliderBuilder::cuboid(half_extents[0], half_extents[1])
+            }
+            Shape::Ball { radius } => ColliderBuilder::ball(*radius),
+            Shape::Polyline { points } => ColliderBuilder::polyline(
+                points.iter().map(|[x, y]| point![*x, *y]).collect(),
+                None,
+            ),
+            Shape::Heightfield { heights, scale } => ColliderBuilder::heightfield(
+                DVector::from_vec(heights.clone()),
+                vector![scale[0], scale[1]],
+            ),
+        }
+    }
+
+    /// The shape as connected line segments, in the shape's local coordinates.
+    /// Returns `None` for shapes that have an area.
+    pub fn segments(&self) -> Option<Vec<[Real; 2]>> {
+        match self {
+            Shape::Cuboid { .. } | Shape::Ball { .. } => None,
+            Shape::Polyline { points } => Some(points.clone()),
+            Shape::Heightfield { heights, scale } => {
+                let step = match heights.len() {
+                    0 | 1 => 0.0,
+                    len => scale[0] / (len - 1) as Real,
+                };
+                Some(
+                    heights
+                        .iter()
+                        .enumerate()
+                        .map(|(i, height)| [-scale[0] / 2.0 + i as Real * step, height * scale[1]])
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+fn finite(point: &[Real; 2]) -> bool {
+    point[0].is_finite() && point[1].is_finite()
+}
@@ -0,0 +1,141 @@
+//! Key bindings: which keys, mouse buttons and gamepad buttons and axes control a local player.
+//!
+//! Bindings are part of the client's [`crate::config::Settings`]. Several players can share
+//! one keyboard by giving each of them their own keys.
+
+use crate::game::{input::quantize_steering, PlayerAction, PlayerInput};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Something that can be pressed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// A button of the player's gamepad.
+    Gamepad(GamepadButtonType),
+    /// An axis of the player's gamepad, pressed when it is past the threshold.
+    /// A negative threshold means the axis has to be below it.
+    Axis(GamepadAxisType, f32),
+}
+
+/// The controls of one local player. Each action can have several bindings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bindings {
+    /// Which gamepad the gamepad bindings refer to.
+    pub gamepad: usize,
+    pub left: Vec<Binding>,
+    pub right: Vec<Binding>,
+    /// Gamepad axes for analog steering. Only used while no left or right binding is pressed.
+    pub steering_axes: Vec<GamepadAxisType>,
+    pub jump: Vec<Binding>,
+    pub dash: Vec<Binding>,
+    pub brake: Vec<Binding>,
+    pub ground_pound: Vec<Binding>,
+}
+
+/// The input devices that bindings read.
+pub struct InputDevices<'a> {
+    pub keys: &'a Input<KeyCode>,
+    pub mouse_buttons: &'a Input<MouseButton>,
+    pub gamepad_buttons: &'a Input<GamepadButton>,
+    pub gamepad_axes: &'a Axis<GamepadAxis>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            gamepad: 0,
+            left: vec![Binding::Key(KeyCode::Left)],
+            right: vec![Binding::Key(KeyCode::Right)],
+            steering_axes: vec![GamepadAxisType::LeftStickX],
+            jump: vec![
+                Binding::Key(KeyCode::Up),
+                Binding::Gamepad(GamepadButtonType::South),
+            ],
+            dash: vec![
+                Binding::Key(KeyCode::Space),
+                Binding::Gamepad(GamepadButtonType::West),
+            ],
+            brake: vec![
+                Binding::Key(KeyCode::LShift),
+                Binding::Gamepad(GamepadButtonType::LeftTrigger2),
+            ],
+            ground_pound: vec![
+                Binding::Key(KeyCode::Down),
+                Binding::Gamepad(GamepadButtonType::East),
+            ],
+        }
+    }
+}
+
+impl Bindings {
+    /// The bindings of an action.
+    pub fn action(&self, action: PlayerAction) -> &[Binding] {
+        match action {
+            PlayerAction::Jump => &self.jump,
+            PlayerAction::Dash => &self.dash,
+            PlayerAction::Brake => &self.brake,
+            PlayerAction::GroundPound => &self.ground_pound,
+        }
+    }
+
+    /// The player's input from the current state of the input devices.
+    pub fn input(&self, devices: &InputDevices) -> PlayerInput {
+        let gamepad = Gamepad(self.gamepad);
+        let any_pressed = |bindings: &[Binding]| {
+            bindings.iter().any(|binding| match *binding {
+                Binding::Key(key) => devices.keys.pressed(key),
+                Binding::Mouse(button) => devices.mouse_buttons.pressed(button),
+                Binding::Gamepad(button) => devices
+                    .gamepad_buttons
+                    .pressed(GamepadButton(gamepad, button)),
+                Binding::Axis(axis, threshold) => {
+                    let value = devices
+                        .gamepad_axes
+                        .get(GamepadAxis(gamepad, axis))
+                        .unwrap_or(0.0);
+                    if threshold < 0.0 {
+                        value < threshold
+                    } else {
+                        value > threshold
+                    }
+                }
+            })
+        };
+
+        let digital_steering = any_pressed(&self.right) as i32 - any_pressed(&self.left) as i32;
+        let steering = match digital_steering {
+            0 => self
+                .steering_axes
+                .iter()
+                .filter_map(|axis| devices.gamepad_axes.get(GamepadAxis(gamepad, *axis)))
+                // The axis that is pushed furthest wins
+                .fold(0.0, |steering: f32, value| {
+                    if value.abs() > steering.abs() {
+                        value
+                    } else {
+                        steering
+                    }
+                }),
+            steering => steering as f32,
+        };
+        let mut input = PlayerInput {
+            steering: quantize_steering(steering),
+            ..Default::default()
+        };
+        for action in [
+            PlayerAction::Jump,
+            PlayerAction::Dash,
+            PlayerAction::Brake,
+            PlayerAction::GroundPound,
+        ]
+        .iter()
+        {
+            input.set_action(*action, any_pressed(self.action(*action)));
+        }
+        input
+    }
+}
@@ -0,0 +1,377 @@
+//! Configuration for server and client, loaded from command line flags and an optional TOML file.
+//! Command line flags override values from the file.
+
+use crate::{
+    arena::Arena,
+    bindings::Bindings,
+    crystalorb_bevy_networking_turbulence::{
+        bevy_networking_turbulence::{
+            MessageChannelMode, MessageChannelSettings, ReliableChannelSettings,
+        },
+        ClockSyncChannelSettings, CommandChannelSettings, SnapshotChannelSettings,
+    },
+    game::{codec::SnapshotPrecision, WorldSettings},
+    match_state::MatchSettings,
+    rules::GameRules,
+    SERVER_PORT, TIMESTEP,
+};
+use crystalorb::Config;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Options {
+    /// Path to a TOML configuration file.
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Address to listen on (server) or connect to (client).
+    /// Defaults to the address of this machine.
+    #[structopt(long)]
+    pub address: Option<IpAddr>,
+    /// Port to listen on (server) or connect to (client).
+    #[structopt(long)]
+    pub port: Option<u16>,
+    /// Simulation timestep in seconds.
+    #[structopt(long)]
+    pub timestep: Option<f64>,
+    /// Path to an arena definition file (server only).
+    /// Repeat to play several arenas in rotation.
+    #[structopt(long = "arena", parse(from_os_str))]
+    pub arenas: Vec<PathBuf>,
+    /// Join as a spectator (client only).
+    #[structopt(long)]
+    pub spectate: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// The configuration file the settings were loaded from, if any.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    pub address: Option<IpAddr>,
+    pub port: u16,
+    pub timestep: f64,
+    /// Send the complete physics state in snapshots, so that clients resimulate exactly like
+    /// the server. Makes snapshots much larger. Only used by the server, clients get it with the
+    /// world.
+    pub full_physics_snapshots: bool,
+    /// Paths to arena definition files, played in this order.
+    /// Uses the built-in box arena if empty.
+    /// Only used by the server, clients get the arenas from the server.
+    pub arenas: Vec<PathBuf>,
+    /// Only used by the server, clients get the match settings with the world.
+    #[serde(rename = "match")]
+    pub match_settings: MatchSettings,
+    /// How precisely the server sends the players' positions and velocities.
+    /// Only used by the server, the precision is part of each snapshot.
+    pub snapshot_precision: SnapshotPrecision,
+    /// Only used by the server, clients get the rules from the server.
+    /// The server reloads them from the configuration file between rounds.
+    pub rules: GameRules,
+    /// Key bindings of the local players. The client asks the server for a player for each entry,
+    /// up to [`crate::game::MAX_LOCAL_PLAYERS`]. Only used by the client.
+    pub bindings: Vec<Bindings>,
+    /// Join without players, only watching. Only used by the client.
+    pub spectate: bool,
+    pub stats: StatsSettings,
+    pub crystalorb: CrystalOrbSettings,
+    pub command_channel: ChannelSettings,
+    /// Uses crystalorb's default settings if not set.
+    pub snapshot_channel: Option<ChannelSettings>,
+    /// Uses crystalorb's default settings if not set.
+    pub clock_sync_channel: Option<ChannelSettings>,
+}
+
+/// Netcode statistics, see [`crate::stats`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsSettings {
+    /// Show the client's statistics overlay from the start. F3 shows and hides it.
+    pub overlay: bool,
+    /// Font of the overlay, relative to the client's assets directory.
+    /// If it can't be loaded, the statistics are shown in the window title instead.
+    pub font: String,
+    /// Append the client's statistics to this CSV file every second.
+    pub csv: Option<PathBuf>,
+    /// How often the server logs the statistics of each connection, in seconds.
+    pub log_period: f64,
+    /// Serve the server's metrics in the Prometheus format at `http://127.0.0.1:<port>/metrics`.
+    pub metrics_port: Option<u16>,
+}
+
+/// Overrides for [`crystalorb::Config`]. Values that are not set use crystalorb's defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrystalOrbSettings {
+    pub lag_compensation_latency: Option<f64>,
+    pub blend_latency: Option<f64>,
+    pub clock_sync_needed_sample_count: Option<usize>,
+    pub clock_sync_assumed_outlier_rate: Option<f64>,
+    pub clock_sync_request_period: Option<f64>,
+    pub max_tolerable_clock_deviation: Option<f64>,
+    pub snapshot_send_period: Option<f64>,
+    pub update_delta_seconds_max: Option<f64>,
+    pub timestamp_skip_threshold_seconds: Option<f64>,
+    pub fastforward_max_per_step: Option<usize>,
+}
+
+/// Settings for a compressed, reliable message channel.
+/// Make sure the server and all clients use the same values.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelSettings {
+    pub bandwidth: u32,
+    pub recv_window_size: u32,
+    pub send_window_size: u32,
+    pub burst_bandwidth: u32,
+    pub init_send: u32,
+    pub wakeup_time_ms: u64,
+    pub initial_rtt_ms: u64,
+    pub max_rtt_ms: u64,
+    pub rtt_update_factor: f64,
+    pub rtt_resend_factor: f64,
+    pub max_chunk_len: usize,
+    pub message_buffer_size: usize,
+    pub packet_buffer_size: usize,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A value that can't be used, with a description of what is wrong.
+    Invalid(String),
+}
+
+impl Default for StatsSettings {
+    fn default() -> Self {
+        Self {
+            overlay: false,
+            font: "fonts/FiraMono-Medium.ttf".to_string(),
+            csv: None,
+            log_period: 10.0,
+            metrics_port: None,
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            path: None,
+            address: None,
+            port: SERVER_PORT,
+            timestep: TIMESTEP,
+            full_physics_snapshots: false,
+            arenas: Vec::new(),
+            match_settings: Default::default(),
+            snapshot_precision: Default::default(),
+            rules: Default::default(),
+            bindings: vec![Bindings::default()],
+            spectate: false,
+            stats: Default::default(),
+            crystalorb: Default::default(),
+            command_channel: Default::default(),
+            snapshot_channel: None,
+            clock_sync_channel: None,
+        }
+    }
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            bandwidth: 4096,
+            recv_window_size: 1024,
+            send_window_size: 1024,
+            burst_bandwidth: 1024,
+            init_send: 512,
+            wakeup_time_ms: 100,
+            initial_rtt_ms: 200,
+            max_rtt_ms: 2000,
+            rtt_update_factor: 0.1,
+            rtt_resend_factor: 1.5,
+            max_chunk_len: 1024,
+            message_buffer_size: 64,
+            packet_buffer_size: 64,
+        }
+    }
+}
+
+impl Settings {
+    /// Load settings from the command line, and from the configuration file given on the command line.
+    pub fn from_args() -> Result<Self, ConfigError> {
+        Self::from_options(Options::from_args())
+    }
+
+    pub fn from_options(options: Options) -> Result<Self, ConfigError> {
+        let mut settings = match &options.config {
+            Some(path) => Self::load(path)?,
+            None => Self::default(),
+        };
+        settings.path = options.config;
+        if let Some(address) = options.address {
+            settings.address = Some(address);
+        }
+        if let Some(port) = options.port {
+            settings.port = port;
+        }
+        if let Some(timestep) = options.timestep {
+            settings.timestep = timestep;
+        }
+        if !options.arenas.is_empty() {
+            settings.arenas = options.arenas;
+        }
+        if options.spectate {
+            settings.spectate = true;
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Check values that would make the server or client misbehave or panic.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !(self.timestep.is_finite() && self.timestep > 0.0) {
+            return Err(ConfigError::Invalid(format!(
+                "timestep must be a positive number of seconds, not {}",
+                self.timestep
+            )));
+        }
+        if self.bindings.is_empty() {
+            return Err(ConfigError::Invalid(
+                "there must be bindings for at least one local player, use --spectate to watch"
+                    .to_string(),
+            ));
+        }
+        if !(self.stats.log_period.is_finite() && self.stats.log_period > 0.0) {
+            return Err(ConfigError::Invalid(format!(
+                "stats.log_period must be a positive number of seconds, not {}",
+                self.stats.log_period
+            )));
+        }
+        Ok(())
+    }
+
+    /// Load settings from a TOML file. Values that are not in the file get their defaults.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Load the arenas given in the settings, or the built-in arena if none are given.
+    pub fn load_arenas(&self) -> Result<Vec<Arena>, ConfigError> {
+        if self.arenas.is_empty() {
+            return Ok(vec![Arena::default()]);
+        }
+        self.arenas.iter().map(|path| Arena::load(path)).collect()
+    }
+
+    /// The settings that the server's world is simulated with.
+    pub fn world_settings(&self) -> WorldSettings {
+        WorldSettings {
+            timestep: self.timestep,
+            full_physics_snapshots: self.full_physics_snapshots,
+        }
+    }
+
+    pub fn crystal_orb_config(&self) -> Config {
+        let defaults = crate::crystal_orb_config();
+        let overrides = &self.crystalorb;
+        Config {
+            lag_compensation_latency: overrides
+                .lag_compensation_latency
+                .unwrap_or(defaults.lag_compensation_latency),
+            blend_latency: overrides.blend_latency.unwrap_or(defaults.blend_latency),
+            timestep_seconds: self.timestep,
+            clock_sync_needed_sample_count: overrides
+                .clock_sync_needed_sample_count
+                .unwrap_or(defaults.clock_sync_needed_sample_count),
+            clock_sync_assumed_outlier_rate: overrides
+                .clock_sync_assumed_outlier_rate
+                .unwrap_or(defaults.clock_sync_assumed_outlier_rate),
+            clock_sync_request_period: overrides
+                .clock_sync_request_period
+                .unwrap_or(defaults.clock_sync_request_period),
+            max_tolerable_clock_deviation: overrides
+                .max_tolerable_clock_deviation
+                .unwrap_or(defaults.max_tolerable_clock_deviation),
+            snapshot_send_period: overrides
+                .snapshot_send_period
+                .unwrap_or(defaults.snapshot_send_period),
+            update_delta_seconds_max: overrides
+                .update_delta_seconds_max
+                .unwrap_or(defaults.update_delta_seconds_max),
+            timestamp_skip_threshold_seconds: overrides
+                .timestamp_skip_threshold_seconds
+                .unwrap_or(defaults.timestamp_skip_threshold_seconds),
+            fastforward_max_per_step: overrides
+                .fastforward_max_per_step
+                .unwrap_or(defaults.fastforward_max_per_step),
+            ..defaults
+        }
+    }
+
+    pub fn command_channel_settings(&self) -> CommandChannelSettings {
+        CommandChannelSettings(self.command_channel.message_channel_settings(0))
+    }
+
+    pub fn snapshot_channel_settings(&self) -> SnapshotChannelSettings {
+        match &self.snapshot_channel {
+            Some(settings) => SnapshotChannelSettings(settings.message_channel_settings(1)),
+            None => Default::default(),
+        }
+    }
+
+    pub fn clock_sync_channel_settings(&self) -> ClockSyncChannelSettings {
+        match &self.clock_sync_channel {
+            Some(settings) => ClockSyncChannelSettings(settings.message_channel_settings(2)),
+            None => Default::default(),
+        }
+    }
+}
+
+impl ChannelSettings {
+    pub fn message_channel_settings(&self, channel: u8) -> MessageChannelSettings {
+        MessageChannelSettings {
+            channel,
+            channel_mode: MessageChannelMode::Compressed {
+                reliability_settings: ReliableChannelSettings {
+                    bandwidth: self.bandwidth,
+                    recv_window_size: self.recv_window_size,
+                    send_window_size: self.send_window_size,
+                    burst_bandwidth: self.burst_bandwidth,
+                    init_send: self.init_send,
+                    wakeup_time: Duration::from_millis(self.wakeup_time_ms),
+                    initial_rtt: Duration::from_millis(self.initial_rtt_ms),
+                    max_rtt: Duration::from_millis(self.max_rtt_ms),
+                    rtt_update_factor: self.rtt_update_factor,
+                    rtt_resend_factor: self.rtt_resend_factor,
+                },
+                max_chunk_len: self.max_chunk_len,
+            },
+            message_buffer_size: self.message_buffer_size,
+            packet_buffer_size: self.packet_buffer_size,
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Can't read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Can't parse {}: {}", path.display(), e),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -1,7 +1,14 @@
 //! Main game logic.
 //! Based on https://github.com/ErnWong/crystalorb/blob/master/examples/demo/src/lib.rs
 
-use bevy::prelude::{debug, info};
+pub mod codec;
+pub mod events;
+pub mod input;
+pub mod modes;
+pub mod movement;
+pub mod pickups;
+
+use bevy::prelude::{debug, info, warn};
 use crystalorb::{
     command::Command,
     fixed_timestepper::Stepper,
@@ -10,21 +17,75 @@ use crystalorb::{
 use rapier2d::{na::Vector2, prelude::*};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     fmt::{Debug, Display},
     iter::FromIterator,
+    sync::Arc,
+};
+
+use crate::{
+    arena::{Arena, ArenaId, ZoneKind},
+    match_state::{MatchEvent, MatchPhase, MatchSettings, MatchState},
+    rules::GameRules,
+    stats, TIMESTEP,
 };
+use events::{CollisionEvent, EventRecorder};
+pub use input::{PlayerAction, PlayerInput};
+use modes::{GameMode, GameModeKind, StepContext};
+use movement::MovementState;
+use pickups::{pickup_collider, PickupState, PlayerEffects, VisiblePickup};
 
-use crate::TIMESTEP;
+/// How far outside the arena's bounds a player may be before being moved back.
+const OUT_OF_BOUNDS_MARGIN: Real = 50.0;
 
-const GRAVITY: Vector2<Real> = Vector2::new(0.0, -9.81 * 30.0);
+/// For how many steps collision events are kept in the display state.
+const EVENT_HISTORY_STEPS: u32 = 30;
 
 /// Identifies a player. Used as key in maps.
-/// Uses the same value as the client's `client_handle`.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Allocated by [`GameWorld`] when the player spawns, and reused after the player is removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct PlayerId(pub u8);
 
+/// Identifies a client connection. This is the connection handle on the server,
+/// which is also what the client gets as `client_id`.
+pub type ClientHandle = u32;
+
+/// Identifies one of the players of a client, starting at 0.
+pub type LocalPlayer = u8;
+
+/// How many players one client can control.
+pub const MAX_LOCAL_PLAYERS: u8 = 4;
+
+/// What a client connection has been given in the world.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ClientSlot {
+    /// The client controls this player.
+    Player(PlayerId),
+    /// The client could not get a player.
+    Rejected(JoinError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JoinError {
+    /// The match has as many players as it allows, or all player ids are in use.
+    ServerFull,
+}
+
+/// How the world is simulated, the same on the server and all clients.
+/// Set with [`GameCommand::ConfigureMatch`], and sent to the clients with the world.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WorldSettings {
+    /// Seconds per step, crystalorb's timestep.
+    pub timestep: f64,
+    /// Make snapshots contain the complete physics state: contacts, islands, sleeping bodies and
+    /// the CCD solver's state, not only the players' positions and velocities.
+    /// A world that applies such a snapshot continues exactly like the world that took it,
+    /// but the snapshots are much larger.
+    pub full_physics_snapshots: bool,
+}
+
 pub struct GameWorld {
+    settings: WorldSettings,
     pipeline: PhysicsPipeline,
     island_manager: IslandManager,
     broad_phase: BroadPhase,
@@ -33,30 +94,92 @@ pub struct GameWorld {
     colliders: ColliderSet,
     joints: JointSet,
     ccd_solver: CCDSolver,
-    players: HashMap<PlayerId, Player>,
+    /// Ordered by id, so that all processes step and serialize the players in the same order.
+    players: BTreeMap<PlayerId, Player>,
+    /// What each client has been given, one slot per local player.
+    clients: BTreeMap<ClientHandle, Vec<ClientSlot>>,
+    /// Number of commands that could not be applied.
+    /// Not part of the snapshot, so this counts what happened in this process only.
+    dropped_commands: u32,
+    /// The arenas of the match's rotation, in order.
+    arenas: Vec<Arc<Arena>>,
+    arena: Arc<Arena>,
+    arena_id: ArenaId,
+    /// The static bodies that make up the arena.
+    arena_bodies: Vec<RigidBodyHandle>,
+    /// The sensor colliders of the arena's zones, in the order of the arena's zones.
+    arena_zones: Vec<ColliderHandle>,
+    /// The bodies of the arena's pickups, in the order of the arena's pickups.
+    pickup_bodies: Vec<RigidBodyHandle>,
+    /// The sensor colliders of the pickups that are available.
+    pickup_colliders: Vec<Option<ColliderHandle>>,
+    pickups: Vec<PickupState>,
+    match_state: MatchState,
+    mode: GameMode,
+    scores: BTreeMap<PlayerId, i32>,
+    rules: GameRules,
+    /// Rules that take effect when the next round starts.
+    pending_rules: Option<GameRules>,
+    /// Number of steps since the world was created, wrapping around.
+    step_number: u32,
+    /// Collision events of the last steps, with the step they happened in.
+    /// Not part of the snapshot, the events are only for showing effects.
+    recent_events: VecDeque<(u32, CollisionEvent)>,
+}
+
+/// What a collider belongs to.
+enum ColliderOwner {
+    Player(PlayerId),
+    /// Index in the arena's zones.
+    Zone(usize),
+    /// Index in the arena's pickups.
+    Pickup(usize),
+    /// The arena's static shapes.
+    Arena,
 }
 
 pub struct Player {
     body_handle: RigidBodyHandle,
-    _collider_handle: ColliderHandle,
+    collider_handle: ColliderHandle,
     input: PlayerInput,
+    effects: PlayerEffects,
+    /// The density of the player's collider. Follows the rules and the player's effects.
+    density: Real,
+    /// Index of the checkpoint zone that the player reached last in this round.
+    checkpoint: Option<usize>,
+    movement: MovementState,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum GameCommand {
-    SpawnPlayer { client_handle: u32 },
-    Input(PlayerId, PlayerCommand, bool),
+    /// Give a client players to control. Issued by the server.
+    SpawnPlayer {
+        client_handle: ClientHandle,
+        /// How many players the client wants, up to [`MAX_LOCAL_PLAYERS`].
+        local_players: u8,
+    },
+    /// Remove all players of a client, or stop it spectating. Issued by the server.
+    DespawnPlayer { client_handle: ClientHandle },
+    /// The whole input of one of the players controlled by the given client.
+    /// Clients send this when their input changes, at most once per frame.
+    /// Refers to the client rather than the player, so that [`World::command_is_valid`]
+    /// can check that clients only control their own players.
+    SetInput(ClientHandle, LocalPlayer, PlayerInput),
+    /// Start a new match with the given settings, arena rotation and rules. Issued by the server.
+    ConfigureMatch {
+        settings: MatchSettings,
+        world: WorldSettings,
+        rotation: Vec<Arena>,
+        rules: GameRules,
+    },
+    /// Change the rules when the next round starts. Issued by the server.
+    UpdateRules(GameRules),
+    /// Make a client a spectator, removing its players if it has any. Issued by the server.
+    Spectate { client_handle: ClientHandle },
 }
 
 impl Command for GameCommand {}
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
-pub struct PlayerInput {
-    pub jump: bool,
-    pub left: bool,
-    pub right: bool,
-}
-
 impl Display for PlayerId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "P{}", self.0)
@@ -69,16 +192,123 @@ impl PlayerId {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub enum PlayerCommand {
-    Jump,
-    Left,
-    Right,
+impl Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::ServerFull => write!(f, "Server is full"),
+        }
+    }
+}
+
+/// Why the physics state of a snapshot does not fit the rest of the snapshot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhysicsMismatch {
+    /// The physics state is of this arena, which is not the snapshot's.
+    Arena(ArenaId),
+    /// The physics state does not have the same players as the snapshot.
+    Players,
+    /// The physics state does not have as many zones or pickups as the arena.
+    ArenaObjects,
 }
 
+impl Display for PhysicsMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhysicsMismatch::Arena(arena_id) => {
+                write!(f, "Physics state is of arena {}", arena_id)
+            }
+            PhysicsMismatch::Players => write!(f, "Physics state has other players"),
+            PhysicsMismatch::ArenaObjects => {
+                write!(f, "Physics state has other zones or pickups than the arena")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PhysicsMismatch {}
+
+/// Sent encoded by [`codec`], which quantizes and delta-encodes the players.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GameSnapshot {
+    settings: WorldSettings,
+    /// The arenas of the rotation. [`codec`] leaves them out if the baseline has the same ones.
+    arenas: Vec<Arc<Arena>>,
+    arena: ArenaId,
     players: Vec<(PlayerId, PlayerSnapshot)>,
+    clients: Vec<(ClientHandle, Vec<ClientSlot>)>,
+    match_state: MatchState,
+    mode: GameMode,
+    scores: Vec<(PlayerId, i32)>,
+    rules: GameRules,
+    pending_rules: Option<GameRules>,
+    step_number: u32,
+    pickups: Vec<PickupState>,
+    /// The complete physics state, if [`WorldSettings::full_physics_snapshots`] is enabled.
+    physics: Option<PhysicsSnapshot>,
+}
+
+impl GameSnapshot {
+    /// The players' part of the snapshot, which [`codec`] encodes.
+    pub fn players(&self) -> &[(PlayerId, PlayerSnapshot)] {
+        &self.players
+    }
+}
+
+/// Everything rapier keeps between steps, and the handles the world has into it.
+/// Restoring only the bodies' positions and velocities leaves the contacts, islands,
+/// sleeping bodies and CCD state of the old world, so resimulation can diverge.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PhysicsSnapshot {
+    arena: ArenaId,
+    island_manager: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    bodies: RigidBodySet,
+    colliders: ColliderSet,
+    joints: JointSet,
+    ccd_solver: CCDSolver,
+    /// The body and collider of each player.
+    players: Vec<(PlayerId, RigidBodyHandle, ColliderHandle)>,
+    arena_bodies: Vec<RigidBodyHandle>,
+    arena_zones: Vec<ColliderHandle>,
+    pickup_bodies: Vec<RigidBodyHandle>,
+    pickup_colliders: Vec<Option<ColliderHandle>>,
+}
+
+impl PhysicsSnapshot {
+    /// Check that the physics state fits the arena and the players of its snapshot.
+    fn check(
+        &self,
+        arena_id: ArenaId,
+        arena: &Arena,
+        players: &[(PlayerId, PlayerSnapshot)],
+    ) -> Result<(), PhysicsMismatch> {
+        if self.arena != arena_id {
+            return Err(PhysicsMismatch::Arena(self.arena));
+        }
+        if self.arena_zones.len() != arena.zones.len()
+            || self.pickup_bodies.len() != arena.pickups.len()
+            || self.pickup_colliders.len() != arena.pickups.len()
+        {
+            return Err(PhysicsMismatch::ArenaObjects);
+        }
+        let player_ids = self.players.iter().map(|(player_id, _, _)| *player_id);
+        if !player_ids.eq(players.iter().map(|(player_id, _)| *player_id)) {
+            return Err(PhysicsMismatch::Players);
+        }
+        Ok(())
+    }
+}
+
+impl Debug for PhysicsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PhysicsSnapshot {{ {} bodies, {} colliders }}",
+            self.bodies.len(),
+            self.colliders.len()
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -87,11 +317,48 @@ pub struct PlayerSnapshot {
     linvel: Vector2<Real>,
     angvel: Real,
     input: PlayerInput,
+    effects: PlayerEffects,
+    checkpoint: Option<usize>,
+    movement: MovementState,
 }
 
 #[derive(Clone, Debug)]
 pub struct GameDisplayState {
-    pub player_positions: HashMap<PlayerId, Isometry<Real>>,
+    pub arena_id: ArenaId,
+    pub arena: Arc<Arena>,
+    pub player_positions: BTreeMap<PlayerId, Isometry<Real>>,
+    pub player_radius: Real,
+    /// The rules in effect, not the ones waiting for the next round.
+    pub rules: GameRules,
+    /// What each client has been given, one slot per local player.
+    pub clients: BTreeMap<ClientHandle, Vec<ClientSlot>>,
+    /// How many more players can join.
+    pub free_player_slots: usize,
+    pub dropped_commands: u32,
+    pub phase: MatchPhase,
+    /// The current round, starting at 1. Zero during warmup.
+    pub round: u32,
+    /// Time left in the current phase.
+    pub seconds_left: f64,
+    pub mode: GameModeKind,
+    pub scores: BTreeMap<PlayerId, i32>,
+    /// A player that the game mode singles out, such as the player who is "it" in tag.
+    pub marked_player: Option<PlayerId>,
+    pub step_number: u32,
+    /// Collision events of the last steps, with the step they happened in, oldest first.
+    /// Events of a step can show up in several display states, use the step number to only handle them once.
+    pub events: Vec<(u32, CollisionEvent)>,
+    /// The pickups that can be picked up.
+    pub pickups: Vec<VisiblePickup>,
+}
+
+impl Default for WorldSettings {
+    fn default() -> Self {
+        Self {
+            timestep: TIMESTEP,
+            full_physics_snapshots: false,
+        }
+    }
 }
 
 impl Default for GameWorld {
@@ -101,53 +368,19 @@ impl Default for GameWorld {
 }
 
 impl GameWorld {
+    /// A world in the default arena, until the match is configured.
     pub fn new() -> Self {
         let mut bodies = RigidBodySet::new();
         let mut colliders = ColliderSet::new();
 
-        // Walls and floor
-        colliders.insert_with_parent(
-            ColliderBuilder::cuboid(1.0, 100.0).restitution(0.5).build(),
-            bodies.insert(
-                RigidBodyBuilder::new_static()
-                    .translation(vector![0.0, 0.0])
-                    .ccd_enabled(true)
-                    .build(),
-            ),
-            &mut bodies,
-        );
-        colliders.insert_with_parent(
-            ColliderBuilder::cuboid(1.0, 100.0).restitution(0.5).build(),
-            bodies.insert(
-                RigidBodyBuilder::new_static()
-                    .translation(vector![180.0, 0.0])
-                    .ccd_enabled(true)
-                    .build(),
-            ),
-            &mut bodies,
-        );
-        colliders.insert_with_parent(
-            ColliderBuilder::cuboid(180.0, 1.0).restitution(0.5).build(),
-            bodies.insert(
-                RigidBodyBuilder::new_static()
-                    .translation(vector![0.0, 0.0])
-                    .ccd_enabled(true)
-                    .build(),
-            ),
-            &mut bodies,
-        );
-        colliders.insert_with_parent(
-            ColliderBuilder::cuboid(180.0, 1.0).restitution(0.5).build(),
-            bodies.insert(
-                RigidBodyBuilder::new_static()
-                    .translation(vector![0.0, 100.0])
-                    .ccd_enabled(true)
-                    .build(),
-            ),
-            &mut bodies,
-        );
+        let settings = WorldSettings::default();
+        let arena = Arc::new(Arena::default());
+        let arena_id = arena.id();
+        let arena_handles = arena.build(&mut bodies, &mut colliders);
+        let match_settings = MatchSettings::default();
 
-        Self {
+        let mut world = Self {
+            settings,
             pipeline: PhysicsPipeline::new(),
             island_manager: IslandManager::new(),
             broad_phase: BroadPhase::new(),
@@ -156,7 +389,182 @@ impl GameWorld {
             colliders,
             joints: JointSet::new(),
             ccd_solver: CCDSolver::new(),
-            players: HashMap::new(),
+            players: BTreeMap::new(),
+            clients: BTreeMap::new(),
+            dropped_commands: 0,
+            mode: GameMode::new(match_settings.mode),
+            match_state: MatchState::new(match_settings, vec![arena_id], settings.timestep),
+            scores: BTreeMap::new(),
+            rules: GameRules::default(),
+            pending_rules: None,
+            step_number: 0,
+            recent_events: VecDeque::new(),
+            arenas: vec![arena.clone()],
+            arena_id,
+            arena,
+            arena_bodies: arena_handles.bodies,
+            arena_zones: arena_handles.zones,
+            pickup_colliders: vec![None; arena_handles.pickups.len()],
+            pickups: vec![PickupState::default(); arena_handles.pickups.len()],
+            pickup_bodies: arena_handles.pickups,
+        };
+        world.sync_pickup_colliders();
+        world
+    }
+
+    /// Replace the arena's static bodies with those of another arena.
+    fn switch_arena(&mut self, arena_id: ArenaId, arena: Arc<Arena>) {
+        info!("Switching to arena {} ({})", arena.name, arena_id);
+        for body_handle in self.arena_bodies.drain(..) {
+            self.bodies.remove(
+                body_handle,
+                &mut self.island_manager,
+                &mut self.colliders,
+                &mut self.joints,
+            );
+        }
+        let arena_handles = arena.build(&mut self.bodies, &mut self.colliders);
+        self.arena_bodies = arena_handles.bodies;
+        self.arena_zones = arena_handles.zones;
+        // The pickups' colliders were removed with their bodies
+        self.pickup_colliders = vec![None; arena_handles.pickups.len()];
+        self.pickups = vec![PickupState::default(); arena_handles.pickups.len()];
+        self.pickup_bodies = arena_handles.pickups;
+        self.sync_pickup_colliders();
+        for player in self.players.values_mut() {
+            player.checkpoint = None;
+        }
+        self.arena = arena;
+        self.arena_id = arena_id;
+    }
+
+    /// Add colliders for the pickups that are available, and remove those of the pickups that are not.
+    fn sync_pickup_colliders(&mut self) {
+        for (index, pickup) in self.pickups.iter().enumerate() {
+            match (pickup.is_available(), self.pickup_colliders[index]) {
+                (true, None) => {
+                    self.pickup_colliders[index] = Some(self.colliders.insert_with_parent(
+                        pickup_collider(),
+                        self.pickup_bodies[index],
+                        &mut self.bodies,
+                    ));
+                }
+                (false, Some(collider_handle)) => {
+                    self.colliders.remove(
+                        collider_handle,
+                        &mut self.island_manager,
+                        &mut self.bodies,
+                        true,
+                    );
+                    self.pickup_colliders[index] = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Replace a player's collider if its density does not match the rules and the player's effects.
+    fn sync_player_density(&mut self, player_id: PlayerId) {
+        let player = match self.players.get(&player_id) {
+            Some(player) => player,
+            None => return,
+        };
+        let density = self.rules.player_density * player.effects.density_factor();
+        if density != player.density {
+            self.replace_player_collider(player_id, density);
+        }
+    }
+
+    /// Replace a player's collider with one that follows the rules, with the given density.
+    fn replace_player_collider(&mut self, player_id: PlayerId, density: Real) {
+        let player = match self.players.get_mut(&player_id) {
+            Some(player) => player,
+            None => return,
+        };
+        self.colliders.remove(
+            player.collider_handle,
+            &mut self.island_manager,
+            &mut self.bodies,
+            true,
+        );
+        player.collider_handle = self.colliders.insert_with_parent(
+            player_collider(&self.rules, density),
+            player.body_handle,
+            &mut self.bodies,
+        );
+        player.density = density;
+    }
+
+    /// Switch to other rules, and rebuild the players' colliders for them.
+    fn set_rules(&mut self, rules: GameRules) {
+        if rules == self.rules {
+            return;
+        }
+        info!("Using new game rules");
+        self.rules = rules;
+        let player_ids: Vec<PlayerId> = self.players.keys().copied().collect();
+        for player_id in player_ids {
+            let density =
+                self.rules.player_density * self.players[&player_id].effects.density_factor();
+            self.replace_player_collider(player_id, density);
+        }
+    }
+
+    /// Count and log a command that could not be applied.
+    fn drop_command(&mut self, command: &GameCommand, reason: &str) {
+        self.dropped_commands += 1;
+        debug!("Dropping command {:?}: {}", command, reason);
+    }
+
+    /// Find the lowest player id that is not in use, if there is room for another player.
+    /// Both server and clients apply spawn commands, so this must be deterministic.
+    fn allocate_player_id(&self) -> Option<PlayerId> {
+        if self.players.len() >= self.match_state.settings().max_players as usize {
+            return None;
+        }
+        (0..=u8::MAX)
+            .map(PlayerId)
+            .find(|player_id| !self.players.contains_key(player_id))
+    }
+
+    /// How many more players can join.
+    pub fn free_player_slots(&self) -> usize {
+        (self.match_state.settings().max_players as usize).saturating_sub(self.players.len())
+    }
+
+    /// Whether a client controls any players. Clients without players spectate.
+    fn client_has_players(&self, client_handle: ClientHandle) -> bool {
+        self.clients
+            .get(&client_handle)
+            .map_or(false, |slots| slots_have_players(slots))
+    }
+
+    /// Remove the players of a client, and forget the client.
+    /// Returns false if the client is not known.
+    fn remove_client(&mut self, client_handle: ClientHandle) -> bool {
+        let slots = match self.clients.remove(&client_handle) {
+            Some(slots) => slots,
+            None => return false,
+        };
+        for slot in slots {
+            if let ClientSlot::Player(player_id) = slot {
+                info!("Removing player {} for client {}", player_id, client_handle);
+                self.remove_player(player_id);
+            }
+        }
+        true
+    }
+
+    /// The player controlled by the given client as the given local player, if any.
+    fn client_player(
+        &self,
+        client_handle: ClientHandle,
+        local_player: LocalPlayer,
+    ) -> Option<PlayerId> {
+        let slots = self.clients.get(&client_handle)?;
+        match slots.get(local_player as usize) {
+            Some(ClientSlot::Player(player_id)) => Some(*player_id),
+            _ => None,
         }
     }
 
@@ -164,28 +572,313 @@ impl GameWorld {
     fn create_player(&mut self, player_id: PlayerId) {
         let body_handle = self.bodies.insert(
             RigidBodyBuilder::new_dynamic()
-                .translation(vector![10.0, 80.0])
+                .translation(self.arena.spawn_point(player_id.as_usize()))
                 .ccd_enabled(true)
                 .build(),
         );
         let collider_handle = self.colliders.insert_with_parent(
-            ColliderBuilder::ball(10.0)
-                .density(0.1)
-                .restitution(0.5)
-                .build(),
+            player_collider(&self.rules, self.rules.player_density),
             body_handle,
             &mut self.bodies,
         );
         let player = Player {
             body_handle,
-            _collider_handle: collider_handle,
+            collider_handle,
             input: Default::default(),
+            effects: Default::default(),
+            density: self.rules.player_density,
+            checkpoint: None,
+            movement: MovementState::default(),
         };
         self.players.insert(player_id, player);
     }
 
+    /// Move a player back to its last checkpoint or its spawn point, and stop it.
+    fn respawn_player(&mut self, player_id: PlayerId) {
+        let player = match self.players.get(&player_id) {
+            Some(player) => player,
+            None => return,
+        };
+        let spawn_point = match player
+            .checkpoint
+            .and_then(|index| self.arena.zones.get(index))
+        {
+            Some(checkpoint) => vector![checkpoint.position[0], checkpoint.position[1]],
+            None => self.arena.spawn_point(player_id.as_usize()),
+        };
+        if let Some(body) = self.bodies.get_mut(player.body_handle) {
+            body.set_position(Isometry::new(spawn_point, 0.0), true);
+            body.set_linvel(Vector2::zeros(), true);
+            body.set_angvel(0.0, true);
+        }
+    }
+
+    /// Start over with the pending rules, all pickups available,
+    /// and all players at their spawn points without effects.
+    fn reset_round(&mut self) {
+        if let Some(rules) = self.pending_rules.take() {
+            self.set_rules(rules);
+        }
+        for pickup in self.pickups.iter_mut() {
+            *pickup = PickupState::default();
+        }
+        self.sync_pickup_colliders();
+        let player_ids: Vec<PlayerId> = self.players.keys().copied().collect();
+        for player_id in player_ids {
+            if let Some(player) = self.players.get_mut(&player_id) {
+                player.effects = PlayerEffects::default();
+                player.checkpoint = None;
+                player.movement = MovementState::default();
+            }
+            self.sync_player_density(player_id);
+            self.respawn_player(player_id);
+        }
+    }
+
+    /// Handle events that change the world: pickups, checkpoints, goals and knockouts.
+    /// Returns the events that actually happened. A pickup that two players touch in the same
+    /// step goes to the player with the lower id.
+    fn apply_collision_events(&mut self, events: Vec<CollisionEvent>) -> Vec<CollisionEvent> {
+        let playing = self.match_state.phase() == MatchPhase::Playing;
+        let mut applied = Vec::with_capacity(events.len());
+        for event in events {
+            match event {
+                CollisionEvent::PickedUp(player_id, kind, index) => {
+                    let available =
+                        matches!(self.pickups.get(index), Some(pickup) if pickup.is_available());
+                    let player = match self.players.get_mut(&player_id) {
+                        Some(player) if available => player,
+                        _ => continue,
+                    };
+                    player.effects.apply(kind, self.settings.timestep);
+                    self.pickups[index].pick_up(
+                        self.arena.pickups[index].respawn_seconds,
+                        self.settings.timestep,
+                    );
+                    self.sync_player_density(player_id);
+                }
+                CollisionEvent::EnteredZone(player_id, ZoneKind::Checkpoint, index) => {
+                    if let Some(player) = self.players.get_mut(&player_id) {
+                        player.checkpoint = Some(index);
+                    }
+                }
+                CollisionEvent::EnteredZone(player_id, ZoneKind::Goal, _) => {
+                    if playing {
+                        *self.scores.entry(player_id).or_default() += 1;
+                    }
+                    if let Some(player) = self.players.get_mut(&player_id) {
+                        player.checkpoint = None;
+                    }
+                    self.respawn_player(player_id);
+                }
+                CollisionEvent::KnockedOut(player_id) => {
+                    debug!("Player {} is knocked out", player_id);
+                    self.respawn_player(player_id);
+                }
+                _ => {}
+            }
+            applied.push(event);
+        }
+        self.sync_pickup_colliders();
+        applied
+    }
+
+    /// Translate rapier's events from the last physics step, and add knockouts.
+    /// The events are sorted, so they are the same in all processes.
+    fn collision_events(&self, recorder: EventRecorder) -> Vec<CollisionEvent> {
+        let collider_players: HashMap<ColliderHandle, PlayerId> = self
+            .players
+            .iter()
+            .map(|(player_id, player)| (player.collider_handle, *player_id))
+            .collect();
+        let owner = |collider_handle: ColliderHandle| {
+            if let Some(player_id) = collider_players.get(&collider_handle) {
+                return ColliderOwner::Player(*player_id);
+            }
+            if let Some(index) = self
+                .arena_zones
+                .iter()
+                .position(|zone_handle| *zone_handle == collider_handle)
+            {
+                return ColliderOwner::Zone(index);
+            }
+            match self
+                .pickup_colliders
+                .iter()
+                .position(|pickup_handle| *pickup_handle == Some(collider_handle))
+            {
+                Some(index) => ColliderOwner::Pickup(index),
+                None => ColliderOwner::Arena,
+            }
+        };
+
+        let (contacts, intersections) = recorder.into_events();
+        let mut events = Vec::new();
+        for contact in contacts {
+            let (collider1, collider2, started) = match contact {
+                ContactEvent::Started(collider1, collider2) => (collider1, collider2, true),
+                ContactEvent::Stopped(collider1, collider2) => (collider1, collider2, false),
+            };
+            match (owner(collider1), owner(collider2)) {
+                (ColliderOwner::Player(a), ColliderOwner::Player(b)) => {
+                    let (a, b) = (a.min(b), a.max(b));
+                    events.push(if started {
+                        CollisionEvent::PlayersTouched(a, b)
+                    } else {
+                        CollisionEvent::PlayersSeparated(a, b)
+                    });
+                }
+                (ColliderOwner::Player(player_id), ColliderOwner::Arena)
+                | (ColliderOwner::Arena, ColliderOwner::Player(player_id))
+                    if started =>
+                {
+                    events.push(CollisionEvent::HitArena(player_id))
+                }
+                _ => {}
+            }
+        }
+        for intersection in intersections {
+            match (owner(intersection.collider1), owner(intersection.collider2)) {
+                (ColliderOwner::Player(player_id), ColliderOwner::Zone(index))
+                | (ColliderOwner::Zone(index), ColliderOwner::Player(player_id)) => {
+                    let kind = self.arena.zones[index].kind;
+                    events.push(if intersection.intersecting {
+                        CollisionEvent::EnteredZone(player_id, kind, index)
+                    } else {
+                        CollisionEvent::LeftZone(player_id, kind, index)
+                    });
+                }
+                (ColliderOwner::Player(player_id), ColliderOwner::Pickup(index))
+                | (ColliderOwner::Pickup(index), ColliderOwner::Player(player_id))
+                    if intersection.intersecting =>
+                {
+                    let kind = self.arena.pickups[index].kind;
+                    events.push(CollisionEvent::PickedUp(player_id, kind, index));
+                }
+                _ => {}
+            }
+        }
+
+        let mut knocked_out: Vec<PlayerId> = events
+            .iter()
+            .filter_map(|event| match event {
+                CollisionEvent::EnteredZone(player_id, ZoneKind::Hazard, _) => Some(*player_id),
+                _ => None,
+            })
+            .collect();
+        for (player_id, player) in self.players.iter() {
+            if let Some(body) = self.bodies.get(player.body_handle) {
+                if !self
+                    .arena
+                    .bounds
+                    .contains(&body.position().translation.vector, OUT_OF_BOUNDS_MARGIN)
+                {
+                    knocked_out.push(*player_id);
+                }
+            }
+        }
+        events.extend(knocked_out.into_iter().map(CollisionEvent::KnockedOut));
+
+        events.sort();
+        events.dedup();
+        events
+    }
+
+    /// The players that are inside a hill zone, ordered by id.
+    fn players_in_hill(&self) -> Vec<PlayerId> {
+        self.players
+            .iter()
+            .filter(|(_, player)| {
+                let intersections =
+                    match self.narrow_phase.intersections_with(player.collider_handle) {
+                        Some(intersections) => intersections,
+                        None => return false,
+                    };
+                for (collider1, collider2, intersecting) in intersections {
+                    let in_hill = self.arena_zones.iter().zip(self.arena.zones.iter()).any(
+                        |(zone_handle, zone)| {
+                            zone.kind == ZoneKind::Hill
+                                && (*zone_handle == collider1 || *zone_handle == collider2)
+                        },
+                    );
+                    if intersecting && in_hill {
+                        return true;
+                    }
+                }
+                false
+            })
+            .map(|(player_id, _)| *player_id)
+            .collect()
+    }
+
+    fn physics_snapshot(&self) -> PhysicsSnapshot {
+        PhysicsSnapshot {
+            arena: self.arena_id,
+            island_manager: self.island_manager.clone(),
+            broad_phase: self.broad_phase.clone(),
+            narrow_phase: self.narrow_phase.clone(),
+            bodies: self.bodies.clone(),
+            colliders: self.colliders.clone(),
+            joints: self.joints.clone(),
+            ccd_solver: self.ccd_solver.clone(),
+            players: self
+                .players
+                .iter()
+                .map(|(player_id, player)| (*player_id, player.body_handle, player.collider_handle))
+                .collect(),
+            arena_bodies: self.arena_bodies.clone(),
+            arena_zones: self.arena_zones.clone(),
+            pickup_bodies: self.pickup_bodies.clone(),
+            pickup_colliders: self.pickup_colliders.clone(),
+        }
+    }
+
+    /// Replace the physics state with the one from a snapshot.
+    /// Leaves the world as it is if the physics state does not fit its arena and players.
+    fn restore_physics(&mut self, physics: PhysicsSnapshot) -> Result<(), PhysicsMismatch> {
+        let players: Vec<PlayerId> = self.players.keys().copied().collect();
+        if physics.arena != self.arena_id {
+            return Err(PhysicsMismatch::Arena(physics.arena));
+        }
+        if physics.arena_zones.len() != self.arena_zones.len()
+            || physics.pickup_bodies.len() != self.pickup_bodies.len()
+        {
+            return Err(PhysicsMismatch::ArenaObjects);
+        }
+        if !physics
+            .players
+            .iter()
+            .map(|(player_id, _, _)| *player_id)
+            .eq(players.into_iter())
+        {
+            return Err(PhysicsMismatch::Players);
+        }
+        for (player_id, body_handle, collider_handle) in physics.players {
+            if let Some(player) = self.players.get_mut(&player_id) {
+                player.body_handle = body_handle;
+                player.collider_handle = collider_handle;
+                // The snapshot's collider follows the rules and the player's effects
+                player.density = self.rules.player_density * player.effects.density_factor();
+            }
+        }
+        self.island_manager = physics.island_manager;
+        self.broad_phase = physics.broad_phase;
+        self.narrow_phase = physics.narrow_phase;
+        self.bodies = physics.bodies;
+        self.colliders = physics.colliders;
+        self.joints = physics.joints;
+        self.ccd_solver = physics.ccd_solver;
+        self.arena_bodies = physics.arena_bodies;
+        self.arena_zones = physics.arena_zones;
+        self.pickup_bodies = physics.pickup_bodies;
+        self.pickup_colliders = physics.pickup_colliders;
+        Ok(())
+    }
+
     /// Remove a player from the physics world and from [`GameWorld::players`].
     fn remove_player(&mut self, player_id: PlayerId) {
+        self.mode.rules_mut().player_removed(player_id);
+        self.scores.remove(&player_id);
         if let Some(player) = self.players.remove(&player_id) {
             self.bodies.remove(
                 player.body_handle,
@@ -205,33 +898,159 @@ impl World for GameWorld {
     fn command_is_valid(command: &Self::CommandType, client_id: usize) -> bool {
         match command {
             GameCommand::SpawnPlayer { .. } => false,
-            GameCommand::Input(player_id, _, _) => player_id.as_usize() == client_id,
+            GameCommand::DespawnPlayer { .. } => false,
+            // Which player the client controls is looked up in apply_command,
+            // which drops the input of spectators
+            GameCommand::SetInput(client_handle, _, _) => *client_handle as usize == client_id,
+            GameCommand::ConfigureMatch { .. } => false,
+            GameCommand::UpdateRules(_) => false,
+            GameCommand::Spectate { .. } => false,
         }
     }
 
     fn apply_command(&mut self, command: &Self::CommandType) {
         match command {
-            GameCommand::SpawnPlayer { client_handle } => {
-                let player_id = PlayerId(*client_handle as u8);
-                assert!(!self.players.contains_key(&player_id));
-                info!("Using player id {} for client {}", player_id, client_handle);
-                self.create_player(player_id);
+            GameCommand::SpawnPlayer {
+                client_handle,
+                local_players,
+            } => {
+                // Spectators can become players
+                if self.client_has_players(*client_handle) {
+                    self.drop_command(command, "client already has players");
+                    return;
+                }
+                let count = (*local_players).max(1).min(MAX_LOCAL_PLAYERS);
+                // All players of a client join together, or none of them
+                if count as usize > self.free_player_slots() {
+                    warn!(
+                        "No room for the {} players of client {}, it spectates",
+                        count, client_handle
+                    );
+                    self.clients.insert(
+                        *client_handle,
+                        vec![ClientSlot::Rejected(JoinError::ServerFull); count as usize],
+                    );
+                    return;
+                }
+                let mut slots = Vec::new();
+                for local_player in 0..count {
+                    slots.push(match self.allocate_player_id() {
+                        Some(player_id) => {
+                            info!(
+                                "Using player id {} for client {} player {}",
+                                player_id, client_handle, local_player
+                            );
+                            self.create_player(player_id);
+                            ClientSlot::Player(player_id)
+                        }
+                        None => {
+                            warn!(
+                                "No player id available for client {} player {}",
+                                client_handle, local_player
+                            );
+                            ClientSlot::Rejected(JoinError::ServerFull)
+                        }
+                    });
+                }
+                self.clients.insert(*client_handle, slots);
+            }
+            GameCommand::DespawnPlayer { client_handle } => {
+                if !self.remove_client(*client_handle) {
+                    self.drop_command(command, "unknown client");
+                }
+            }
+            GameCommand::Spectate { client_handle } => {
+                info!("Client {} is spectating", client_handle);
+                self.remove_client(*client_handle);
+                self.clients.insert(*client_handle, Vec::new());
             }
-            GameCommand::Input(player_id, command, value) => {
-                let player_input = &mut self.players.get_mut(player_id).unwrap().input;
-                match command {
-                    PlayerCommand::Jump => player_input.jump = *value,
-                    PlayerCommand::Left => player_input.left = *value,
-                    PlayerCommand::Right => player_input.right = *value,
+            GameCommand::SetInput(client_handle, local_player, player_input) => {
+                let player = match self.client_player(*client_handle, *local_player) {
+                    Some(player_id) => self.players.get_mut(&player_id),
+                    None => None,
+                };
+                match player {
+                    Some(player) => player.input = *player_input,
+                    None => self.drop_command(command, "client has no player"),
                 }
             }
+            GameCommand::ConfigureMatch {
+                settings,
+                world,
+                rotation,
+                rules,
+            } => {
+                // Clients apply the server's commands too, and a bad arena would panic in rapier
+                if let Some(error) = rotation.iter().find_map(|arena| arena.validate().err()) {
+                    self.drop_command(command, &error.to_string());
+                    return;
+                }
+                self.settings = *world;
+                self.arenas = rotation.iter().cloned().map(Arc::new).collect();
+                let arena_ids = self.arenas.iter().map(|arena| arena.id()).collect();
+                self.match_state = MatchState::new(settings.clone(), arena_ids, world.timestep);
+                self.mode = GameMode::new(settings.mode);
+                self.pending_rules = Some(rules.clone());
+                self.scores.clear();
+                if let Some(arena_id) = self.match_state.arena() {
+                    if arena_id != self.arena_id {
+                        self.switch_arena(arena_id, self.arenas[0].clone());
+                    }
+                }
+                self.reset_round();
+            }
+            GameCommand::UpdateRules(rules) => {
+                self.pending_rules = Some(rules.clone());
+            }
         }
     }
 
     fn apply_snapshot(&mut self, snapshot: Self::SnapshotType) {
+        stats::SNAPSHOTS_APPLIED.add(1);
+        // The arenas come from the server, and a bad one would panic in rapier
+        if snapshot.arenas != self.arenas {
+            if let Some(error) = snapshot
+                .arenas
+                .iter()
+                .find_map(|arena| arena.validate().err())
+            {
+                warn!("Discarding snapshot: {}", error);
+                return;
+            }
+        }
+        let arena = if snapshot.arena == self.arena_id {
+            self.arena.clone()
+        } else {
+            match find_arena(&snapshot.arenas, snapshot.arena) {
+                Some(arena) => arena,
+                None => {
+                    warn!(
+                        "Discarding snapshot: arena {} is not in its rotation",
+                        snapshot.arena
+                    );
+                    return;
+                }
+            }
+        };
+        // Half a snapshot would leave the bodies out of step with the rest of the world
+        if let Some(physics) = &snapshot.physics {
+            if let Err(error) = physics.check(snapshot.arena, &arena, &snapshot.players) {
+                warn!("Discarding snapshot: {}", error);
+                return;
+            }
+        }
+        self.settings = snapshot.settings;
+        self.arenas = snapshot.arenas;
+        if snapshot.arena != self.arena_id {
+            self.switch_arena(snapshot.arena, arena);
+        }
+        // Before the players are created, so they get colliders for these rules
+        self.set_rules(snapshot.rules);
+        self.pending_rules = snapshot.pending_rules;
+
         let snapshot_players =
-            HashSet::<PlayerId>::from_iter(snapshot.players.iter().map(|(n, _)| *n));
-        let current_players = HashSet::from_iter(self.players.keys().copied());
+            BTreeSet::<PlayerId>::from_iter(snapshot.players.iter().map(|(n, _)| *n));
+        let current_players = BTreeSet::from_iter(self.players.keys().copied());
 
         // Create objects for all players in the snapshot which are not already in the game world
         for player_id in snapshot_players.difference(&current_players) {
@@ -247,70 +1066,174 @@ impl World for GameWorld {
 
         // Update players
         for (player_id, player_snapshot) in snapshot.players.iter() {
-            let player = self.players.get_mut(player_id).unwrap();
-            let body = self.bodies.get_mut(player.body_handle).unwrap();
+            let player = match self.players.get_mut(player_id) {
+                Some(player) => player,
+                None => {
+                    warn!("Player {} from snapshot is missing", player_id);
+                    continue;
+                }
+            };
+            let body = match self.bodies.get_mut(player.body_handle) {
+                Some(body) => body,
+                None => {
+                    warn!("Body for player {} is missing", player_id);
+                    continue;
+                }
+            };
             body.set_position(player_snapshot.position, true);
             body.set_linvel(player_snapshot.linvel, true);
             body.set_angvel(player_snapshot.angvel, true);
             player.input = player_snapshot.input;
+            player.effects = player_snapshot.effects;
+            player.checkpoint = player_snapshot.checkpoint;
+            player.movement = player_snapshot.movement;
+            self.sync_player_density(*player_id);
         }
+
+        if snapshot.pickups.len() == self.pickups.len() {
+            self.pickups = snapshot.pickups;
+            self.sync_pickup_colliders();
+        } else {
+            warn!("Pickups from snapshot do not match arena {}", self.arena_id);
+        }
+
+        if let Some(physics) = snapshot.physics {
+            // Checked above, so the world fits the physics state by now
+            if let Err(error) = self.restore_physics(physics) {
+                warn!(
+                    "Physics state from snapshot does not fit the world: {}",
+                    error
+                );
+            }
+        }
+
+        self.clients = snapshot.clients.into_iter().collect();
+        self.match_state = snapshot.match_state;
+        self.mode = snapshot.mode;
+        self.scores = snapshot.scores.into_iter().collect();
+        self.step_number = snapshot.step_number;
     }
 
     fn snapshot(&self) -> Self::SnapshotType {
         let players = self
             .players
             .iter()
-            .map(|(player_id, player)| {
-                let body = self.bodies.get(player.body_handle).unwrap();
-                (
+            .filter_map(|(player_id, player)| {
+                let body = self.bodies.get(player.body_handle)?;
+                Some((
                     *player_id,
                     PlayerSnapshot {
                         position: *body.position(),
                         linvel: *body.linvel(),
                         angvel: body.angvel(),
                         input: player.input,
+                        effects: player.effects,
+                        checkpoint: player.checkpoint,
+                        movement: player.movement,
                     },
-                )
+                ))
             })
             .collect();
-        GameSnapshot { players }
+        let clients = self
+            .clients
+            .iter()
+            .map(|(client_handle, slots)| (*client_handle, slots.clone()))
+            .collect();
+        GameSnapshot {
+            settings: self.settings,
+            arenas: self.arenas.clone(),
+            arena: self.arena_id,
+            players,
+            clients,
+            match_state: self.match_state.clone(),
+            mode: self.mode.clone(),
+            scores: self
+                .scores
+                .iter()
+                .map(|(player_id, score)| (*player_id, *score))
+                .collect(),
+            rules: self.rules.clone(),
+            pending_rules: self.pending_rules.clone(),
+            step_number: self.step_number,
+            pickups: self.pickups.clone(),
+            physics: if self.settings.full_physics_snapshots {
+                Some(self.physics_snapshot())
+            } else {
+                None
+            },
+        }
     }
 
     fn display_state(&self) -> Self::DisplayStateType {
         let player_positions = self
             .players
             .iter()
-            .map(|(player_id, player)| {
-                (
-                    *player_id,
-                    *self.bodies.get(player.body_handle).unwrap().position(),
-                )
+            .filter_map(|(player_id, player)| {
+                let body = self.bodies.get(player.body_handle)?;
+                Some((*player_id, *body.position()))
             })
             .collect();
-        GameDisplayState { player_positions }
+        GameDisplayState {
+            arena_id: self.arena_id,
+            arena: self.arena.clone(),
+            player_positions,
+            player_radius: self.rules.player_radius,
+            rules: self.rules.clone(),
+            clients: self.clients.clone(),
+            free_player_slots: self.free_player_slots(),
+            dropped_commands: self.dropped_commands,
+            phase: self.match_state.phase(),
+            round: self.match_state.round(),
+            seconds_left: self.match_state.seconds_left(),
+            mode: self.mode.kind(),
+            scores: self.scores.clone(),
+            marked_player: self.mode.rules().marked_player(),
+            step_number: self.step_number,
+            events: self.recent_events.iter().copied().collect(),
+            pickups: self
+                .pickups
+                .iter()
+                .zip(self.arena.pickups.iter())
+                .enumerate()
+                .filter(|(_, (pickup, _))| pickup.is_available())
+                .map(|(index, (_, spawn))| VisiblePickup {
+                    index,
+                    kind: spawn.kind,
+                    position: spawn.position,
+                })
+                .collect(),
+        }
     }
 }
 
 impl Stepper for GameWorld {
     fn step(&mut self) {
+        stats::STEPS.add(1);
+        let timestep = self.settings.timestep;
         for player in &mut self.players.values_mut() {
-            let body = self.bodies.get_mut(player.body_handle).unwrap();
-            body.apply_force(
-                Vector2::new(
-                    ((player.input.right as i32) - (player.input.left as i32)) as f32 * 4000.0,
-                    0.0,
-                ),
-                true,
+            let body = match self.bodies.get_mut(player.body_handle) {
+                Some(body) => body,
+                None => continue,
+            };
+            // The narrow phase still has the contacts of the last step
+            player.movement.update_contacts(
+                &self.rules.movement,
+                &self.narrow_phase,
+                player.collider_handle,
+            );
+            player.movement.apply(
+                &self.rules.movement,
+                &player.input,
+                &mut player.effects,
+                body,
+                timestep,
             );
-            if player.input.jump {
-                body.apply_impulse(Vector2::new(0.0, 4000.0), true);
-                player.input.jump = false;
-            }
         }
+        let recorder = EventRecorder::default();
         self.pipeline.step(
-            &GRAVITY,
+            &self.rules.gravity_vector(),
             &IntegrationParameters {
-                dt: TIMESTEP as f32,
+                dt: timestep as f32,
                 ..Default::default()
             },
             &mut self.island_manager,
@@ -321,11 +1244,93 @@ impl Stepper for GameWorld {
             &mut self.joints,
             &mut self.ccd_solver,
             &(),
-            &(),
+            &recorder,
         );
+        self.step_number = self.step_number.wrapping_add(1);
+
+        // Count down effects and pickup respawns
+        let player_ids: Vec<PlayerId> = self.players.keys().copied().collect();
+        for player_id in player_ids {
+            if let Some(player) = self.players.get_mut(&player_id) {
+                player.effects.step();
+            }
+            self.sync_player_density(player_id);
+        }
+        let mut pickups_respawned = false;
+        for pickup in self.pickups.iter_mut() {
+            pickups_respawned |= pickup.step();
+        }
+        if pickups_respawned {
+            self.sync_pickup_colliders();
+        }
+
+        let events = self.collision_events(recorder);
+        let events = self.apply_collision_events(events);
+        let in_hill = self.players_in_hill();
+
+        if self.match_state.phase() == MatchPhase::Playing {
+            let player_ids: Vec<PlayerId> = self.players.keys().copied().collect();
+            self.mode.rules_mut().step(&mut StepContext {
+                players: &player_ids,
+                in_hill: &in_hill,
+                events: &events,
+                scores: &mut self.scores,
+                timestep,
+            });
+        }
+
+        let step_number = self.step_number;
+        self.recent_events
+            .extend(events.into_iter().map(|event| (step_number, event)));
+        while let Some((oldest, _)) = self.recent_events.front() {
+            if step_number.wrapping_sub(*oldest) < EVENT_HISTORY_STEPS {
+                break;
+            }
+            self.recent_events.pop_front();
+        }
+
+        match self.match_state.step() {
+            Some(MatchEvent::RoundStarted) => {
+                let round = self.match_state.round();
+                info!("Round {} started", round);
+                if round == 1 {
+                    self.scores.clear();
+                }
+                let player_ids: Vec<PlayerId> = self.players.keys().copied().collect();
+                self.mode.rules_mut().round_started(round, &player_ids);
+                self.reset_round();
+            }
+            Some(MatchEvent::ArenaChanged(arena_id)) => {
+                if arena_id != self.arena_id {
+                    match find_arena(&self.arenas, arena_id) {
+                        Some(arena) => self.switch_arena(arena_id, arena),
+                        None => warn!("Arena {} is not in the rotation", arena_id),
+                    }
+                }
+                self.reset_round();
+            }
+            None => {}
+        }
+    }
+}
+
+impl GameDisplayState {
+    /// Whether a client has been placed in the world without players: it spectates,
+    /// or there was no room for its players when it joined.
+    pub fn is_spectating(&self, client_handle: ClientHandle) -> bool {
+        self.clients
+            .get(&client_handle)
+            .map_or(false, |slots| !slots_have_players(slots))
     }
 }
 
+/// Whether any of a client's slots has a player.
+fn slots_have_players(slots: &[ClientSlot]) -> bool {
+    slots
+        .iter()
+        .any(|slot| matches!(slot, ClientSlot::Player(_)))
+}
+
 impl DisplayState for GameDisplayState {
     fn from_interpolation(state1: &Self, state2: &Self, t: f64) -> Self {
         // Use all players from state1. If there is a player in state2 but not in state1, it will not be included.
@@ -337,7 +1342,40 @@ impl DisplayState for GameDisplayState {
             });
         }
         GameDisplayState {
+            arena_id: state1.arena_id,
+            arena: state1.arena.clone(),
             player_positions: interpolated_positions,
+            player_radius: state1.player_radius,
+            rules: state1.rules.clone(),
+            clients: state1.clients.clone(),
+            free_player_slots: state1.free_player_slots,
+            dropped_commands: state2.dropped_commands,
+            phase: state1.phase,
+            round: state1.round,
+            seconds_left: if state1.phase == state2.phase {
+                state1.seconds_left + (state2.seconds_left - state1.seconds_left) * t
+            } else {
+                state1.seconds_left
+            },
+            mode: state1.mode,
+            scores: state2.scores.clone(),
+            marked_player: state2.marked_player,
+            step_number: state2.step_number,
+            events: state2.events.clone(),
+            pickups: state2.pickups.clone(),
         }
     }
 }
+
+/// The arena of the rotation with this id.
+fn find_arena(arenas: &[Arc<Arena>], arena_id: ArenaId) -> Option<Arc<Arena>> {
+    arenas.iter().find(|arena| arena.id() == arena_id).cloned()
+}
+
+fn player_collider(rules: &GameRules, density: Real) -> Collider {
+    ColliderBuilder::ball(rules.player_radius)
+        .density(density)
+        .restitution(rules.player_restitution)
+        .active_events(ActiveEvents::CONTACT_EVENTS | ActiveEvents::INTERSECTION_EVENTS)
+        .build()
+}
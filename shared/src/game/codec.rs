@@ -0,0 +1,549 @@
+//! Compact encoding of snapshots.
+//!
+//! The players' positions, angles and velocities are quantized to a configurable number of
+//! fractional bits, and written as variable-length integers. Players can also be delta-encoded
+//! against a baseline snapshot that the receiver already has, and the arenas are left out if the
+//! baseline has the same ones, see [`encode`].
+//!
+//! The server numbers the snapshots it sends, and encodes them for each client against the
+//! newest snapshot that the client has acknowledged, see [`SentSnapshots`]. Crystalorb sends
+//! snapshots unreliably and does not say which ones have arrived, so the clients acknowledge
+//! them on the handshake channel, see [`ReceivedSnapshots`].
+
+use super::{ClientHandle, GameSnapshot, PlayerId, PlayerSnapshot};
+use crystalorb::timestamp::{Timestamp, Timestamped};
+use rapier2d::{na::Vector2, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    f32::consts::PI,
+    fmt::{self, Display},
+};
+
+/// How many snapshots are kept as baselines, on the server and on the clients.
+/// At the default snapshot rate, this is a few seconds.
+const HISTORY: usize = 64;
+
+/// More fractional bits than this would not fit the values of an `f32`.
+const MAX_BITS: u8 = 16;
+
+/// The player is delta-encoded against the same player in the baseline.
+const DELTA: u8 = 1;
+/// The player's input, effects and movement state are the same as in the baseline.
+const SAME_STATE: u8 = 1 << 1;
+
+/// How precisely the players' positions and velocities are sent. Only used by the server,
+/// the precision is part of each snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotPrecision {
+    /// Quantize positions, angles and velocities. If false, they are sent with full precision.
+    pub quantize: bool,
+    /// Positions are rounded to 1 / 2^position_bits units.
+    pub position_bits: u8,
+    /// Angles are rounded to a full turn / 2^angle_bits.
+    pub angle_bits: u8,
+    /// Velocities are rounded to 1 / 2^velocity_bits units (or radians) per second.
+    pub velocity_bits: u8,
+}
+
+/// A snapshot as it is sent to one client, encoded by [`SentSnapshots::encode`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotMessage {
+    /// The snapshot's number, for acknowledging it and encoding against it.
+    pub id: u32,
+    /// The number of the snapshot it is encoded against, if any.
+    pub baseline: Option<u32>,
+    pub timestamp: Timestamp,
+    pub bytes: Vec<u8>,
+}
+
+/// The snapshots that the server has sent, and which of them each client has acknowledged.
+/// A resource on the server.
+pub struct SentSnapshots {
+    precision: SnapshotPrecision,
+    next_id: u32,
+    /// The snapshots that have been sent, newest last.
+    history: VecDeque<(u32, Timestamp, GameSnapshot)>,
+    /// The snapshots that each client has acknowledged.
+    acknowledged: HashMap<ClientHandle, BTreeSet<u32>>,
+}
+
+/// The snapshots that a client has received, to decode the ones encoded against them.
+/// A resource on the client.
+#[derive(Default)]
+pub struct ReceivedSnapshots {
+    /// The snapshots that have been decoded, newest last.
+    history: VecDeque<(u32, GameSnapshot)>,
+    /// Snapshots that have been decoded, but not acknowledged to the server yet.
+    unacknowledged: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// The data ended before the snapshot did.
+    Truncated,
+    /// The snapshot was encoded against a baseline, but none was given.
+    MissingBaseline,
+    /// A player was encoded against a player that is not in the baseline.
+    NotInBaseline(PlayerId),
+    /// The snapshot was encoded against a snapshot that this client has not received.
+    UnknownBaseline(u32),
+    Bincode(bincode::Error),
+}
+
+impl Default for SnapshotPrecision {
+    fn default() -> Self {
+        Self {
+            quantize: true,
+            position_bits: 6,
+            angle_bits: 12,
+            velocity_bits: 4,
+        }
+    }
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Truncated => write!(f, "Snapshot data is truncated"),
+            CodecError::MissingBaseline => write!(f, "Snapshot needs a baseline"),
+            CodecError::NotInBaseline(player_id) => {
+                write!(f, "Player {} is not in the baseline", player_id)
+            }
+            CodecError::UnknownBaseline(id) => {
+                write!(f, "Snapshot {} is not known as a baseline", id)
+            }
+            CodecError::Bincode(e) => write!(f, "Can't decode snapshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<bincode::Error> for CodecError {
+    fn from(e: bincode::Error) -> Self {
+        CodecError::Bincode(e)
+    }
+}
+
+/// Encode a snapshot with the given precision.
+/// If a baseline is given, the players are delta-encoded against it, the arenas are left out if
+/// they are the same, and the receiver needs the same baseline to decode the snapshot.
+pub fn encode(
+    snapshot: &GameSnapshot,
+    baseline: Option<&GameSnapshot>,
+    precision: SnapshotPrecision,
+) -> Vec<u8> {
+    let players = encode_players(
+        &snapshot.players,
+        baseline.map(|baseline| &baseline.players[..]),
+        precision,
+    );
+    let same_arenas = matches!(baseline, Some(baseline) if baseline.arenas == snapshot.arenas);
+    let rest = GameSnapshot {
+        players: Vec::new(),
+        arenas: if same_arenas {
+            Vec::new()
+        } else {
+            snapshot.arenas.clone()
+        },
+        ..snapshot.clone()
+    };
+    let mut bytes = Vec::with_capacity(players.len() + 64);
+    write_varint(&mut bytes, players.len() as u64);
+    bytes.extend_from_slice(&players);
+    bytes.push(same_arenas as u8);
+    bincode::serialize_into(&mut bytes, &rest).expect("snapshots can always be serialized");
+    bytes
+}
+
+/// Decode a snapshot made by [`encode`], with the baseline it was encoded against, if any.
+pub fn decode(bytes: &[u8], baseline: Option<&GameSnapshot>) -> Result<GameSnapshot, CodecError> {
+    let mut reader = Reader(bytes);
+    let players_len = reader.varint()? as usize;
+    let players = decode_players(
+        reader.bytes(players_len)?,
+        baseline.map(|baseline| &baseline.players[..]),
+    )?;
+    let same_arenas = reader.byte()? != 0;
+    let rest: GameSnapshot = bincode::deserialize(reader.0)?;
+    let arenas = if same_arenas {
+        baseline.ok_or(CodecError::MissingBaseline)?.arenas.clone()
+    } else {
+        rest.arenas
+    };
+    Ok(GameSnapshot {
+        players,
+        arenas,
+        ..rest
+    })
+}
+
+impl SentSnapshots {
+    pub fn new(precision: SnapshotPrecision) -> Self {
+        Self {
+            precision,
+            next_id: 0,
+            history: VecDeque::new(),
+            acknowledged: HashMap::new(),
+        }
+    }
+
+    /// Record that a client has received these snapshots.
+    /// Until it has acknowledged one, the client's snapshots are sent without a baseline.
+    pub fn acknowledge(&mut self, client_handle: ClientHandle, snapshot_ids: &[u32]) {
+        let history = &self.history;
+        self.acknowledged.entry(client_handle).or_default().extend(
+            snapshot_ids
+                .iter()
+                .filter(|id| history.iter().any(|(known, _, _)| known == *id)),
+        );
+    }
+
+    /// Forget the acknowledgements of a client that has disconnected or has been rejected.
+    pub fn forget_client(&mut self, client_handle: ClientHandle) {
+        self.acknowledged.remove(&client_handle);
+    }
+
+    /// Encode a snapshot for a client, against the newest snapshot that the client has.
+    pub fn encode(
+        &mut self,
+        client_handle: ClientHandle,
+        snapshot: &Timestamped<GameSnapshot>,
+    ) -> SnapshotMessage {
+        let id = self.number(snapshot);
+        let acknowledged = self.acknowledged.get(&client_handle);
+        let baseline = self
+            .history
+            .iter()
+            .rev()
+            .find(|(id, _, _)| acknowledged.map_or(false, |ids| ids.contains(id)));
+        SnapshotMessage {
+            id,
+            baseline: baseline.map(|(id, _, _)| *id),
+            timestamp: snapshot.timestamp(),
+            bytes: encode(
+                snapshot.inner(),
+                baseline.map(|(_, _, baseline)| baseline),
+                self.precision,
+            ),
+        }
+    }
+
+    /// The number of a snapshot. Crystalorb sends the same snapshot to every client in turn,
+    /// and they all get the same number for it.
+    fn number(&mut self, snapshot: &Timestamped<GameSnapshot>) -> u32 {
+        if let Some((id, timestamp, _)) = self.history.back() {
+            if *timestamp == snapshot.timestamp() {
+                return *id;
+            }
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.history
+            .push_back((id, snapshot.timestamp(), snapshot.inner().clone()));
+        if self.history.len() > HISTORY {
+            if let Some((oldest, _, _)) = self.history.pop_front() {
+                for acknowledged in self.acknowledged.values_mut() {
+                    acknowledged.remove(&oldest);
+                }
+            }
+        }
+        id
+    }
+}
+
+impl ReceivedSnapshots {
+    /// Decode a snapshot from the server, and remember it to acknowledge it.
+    pub fn decode(
+        &mut self,
+        message: &SnapshotMessage,
+    ) -> Result<Timestamped<GameSnapshot>, CodecError> {
+        let baseline = match message.baseline {
+            Some(baseline_id) => Some(
+                self.history
+                    .iter()
+                    .find(|(id, _)| *id == baseline_id)
+                    .map(|(_, baseline)| baseline)
+                    .ok_or(CodecError::UnknownBaseline(baseline_id))?,
+            ),
+            None => None,
+        };
+        let snapshot = decode(&message.bytes, baseline)?;
+
+        if !self.history.iter().any(|(id, _)| *id == message.id) {
+            self.history.push_back((message.id, snapshot.clone()));
+            while self.history.len() > HISTORY {
+                self.history.pop_front();
+            }
+            self.unacknowledged.push(message.id);
+            // Nothing takes them if the client runs without a server connection, e.g. in tests
+            if self.unacknowledged.len() > HISTORY {
+                self.unacknowledged.remove(0);
+            }
+        }
+        Ok(Timestamped::new(snapshot, message.timestamp))
+    }
+
+    /// The snapshots received since this was last called, to acknowledge them to the server.
+    pub fn take_acknowledgements(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.unacknowledged)
+    }
+
+    /// Forget all snapshots, when the client has been welcomed by a server.
+    /// Its snapshots are numbered anew, and can't be decoded against the old ones.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Encode the players of a snapshot, delta-encoded against the baseline's players if given.
+pub fn encode_players(
+    players: &[(PlayerId, PlayerSnapshot)],
+    baseline: Option<&[(PlayerId, PlayerSnapshot)]>,
+    precision: SnapshotPrecision,
+) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8 + players.len() * 16);
+    if precision.quantize {
+        bytes.extend_from_slice(&[
+            1,
+            precision.position_bits.min(MAX_BITS),
+            precision.angle_bits.min(MAX_BITS),
+            precision.velocity_bits.min(MAX_BITS),
+        ]);
+    } else {
+        bytes.push(0);
+    }
+    write_varint(&mut bytes, players.len() as u64);
+    for (player_id, player) in players {
+        // Only quantized values are delta-encoded
+        let base = baseline
+            .and_then(|baseline| find_player(baseline, *player_id))
+            .filter(|_| precision.quantize);
+        let state = player_state(player);
+        let same_state = matches!(base, Some(base) if player_state(base) == state);
+        let mut flags = 0;
+        if base.is_some() {
+            flags |= DELTA;
+        }
+        if same_state {
+            flags |= SAME_STATE;
+        }
+        bytes.extend_from_slice(&[player_id.0, flags]);
+
+        if precision.quantize {
+            let values = quantize(player, &precision);
+            let base_values = base.map(|base| quantize(base, &precision));
+            for (index, value) in values.iter().enumerate() {
+                let base_value = base_values.map(|base| base[index]).unwrap_or(0);
+                write_signed(&mut bytes, value - base_value);
+            }
+        } else {
+            for value in raw_values(player).iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        if !same_state {
+            write_varint(&mut bytes, state.len() as u64);
+            bytes.extend_from_slice(&state);
+        }
+    }
+    bytes
+}
+
+fn decode_players(
+    bytes: &[u8],
+    baseline: Option<&[(PlayerId, PlayerSnapshot)]>,
+) -> Result<Vec<(PlayerId, PlayerSnapshot)>, CodecError> {
+    let mut reader = Reader(bytes);
+    let precision = match reader.byte()? {
+        0 => None,
+        _ => Some(SnapshotPrecision {
+            quantize: true,
+            position_bits: reader.byte()?.min(MAX_BITS),
+            angle_bits: reader.byte()?.min(MAX_BITS),
+            velocity_bits: reader.byte()?.min(MAX_BITS),
+        }),
+    };
+    let count = reader.varint()? as usize;
+    let mut players = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        let player_id = PlayerId(reader.byte()?);
+        let flags = reader.byte()?;
+        let base = if flags & (DELTA | SAME_STATE) != 0 {
+            let baseline = baseline.ok_or(CodecError::MissingBaseline)?;
+            Some(find_player(baseline, player_id).ok_or(CodecError::NotInBaseline(player_id))?)
+        } else {
+            None
+        };
+
+        let (position, linvel, angvel) = match &precision {
+            Some(precision) => {
+                let mut values = [0; 6];
+                let base_values = match base {
+                    Some(base) if flags & DELTA != 0 => quantize(base, precision),
+                    _ => [0; 6],
+                };
+                for (index, value) in values.iter_mut().enumerate() {
+                    *value = base_values[index] + reader.signed()?;
+                }
+                dequantize(&values, precision)
+            }
+            None => {
+                let mut values = [0.0; 6];
+                for value in values.iter_mut() {
+                    *value = reader.f32()?;
+                }
+                (
+                    Isometry::new(vector![values[0], values[1]], values[2]),
+                    vector![values[3], values[4]],
+                    values[5],
+                )
+            }
+        };
+        let (input, effects, checkpoint, movement) = match base {
+            Some(base) if flags & SAME_STATE != 0 => {
+                (base.input, base.effects, base.checkpoint, base.movement)
+            }
+            _ => {
+                let len = reader.varint()? as usize;
+                bincode::deserialize(reader.bytes(len)?)?
+            }
+        };
+        players.push((
+            player_id,
+            PlayerSnapshot {
+                position,
+                linvel,
+                angvel,
+                input,
+                effects,
+                checkpoint,
+                movement,
+            },
+        ));
+    }
+    Ok(players)
+}
+
+fn find_player(
+    players: &[(PlayerId, PlayerSnapshot)],
+    player_id: PlayerId,
+) -> Option<&PlayerSnapshot> {
+    players
+        .iter()
+        .find(|(id, _)| *id == player_id)
+        .map(|(_, player)| player)
+}
+
+/// The part of a player's snapshot that is not quantized.
+fn player_state(player: &PlayerSnapshot) -> Vec<u8> {
+    bincode::serialize(&(
+        player.input,
+        player.effects,
+        player.checkpoint,
+        player.movement,
+    ))
+    .expect("player state can always be serialized")
+}
+
+/// Position, angle, linear velocity and angular velocity.
+fn raw_values(player: &PlayerSnapshot) -> [Real; 6] {
+    [
+        player.position.translation.vector.x,
+        player.position.translation.vector.y,
+        player.position.rotation.angle(),
+        player.linvel.x,
+        player.linvel.y,
+        player.angvel,
+    ]
+}
+
+fn quantize(player: &PlayerSnapshot, precision: &SnapshotPrecision) -> [i64; 6] {
+    let [x, y, angle, vx, vy, angvel] = raw_values(player);
+    let position = scale(precision.position_bits);
+    let velocity = scale(precision.velocity_bits);
+    [
+        (x * position).round() as i64,
+        (y * position).round() as i64,
+        (angle / (2.0 * PI) * scale(precision.angle_bits)).round() as i64,
+        (vx * velocity).round() as i64,
+        (vy * velocity).round() as i64,
+        (angvel * velocity).round() as i64,
+    ]
+}
+
+fn dequantize(
+    values: &[i64; 6],
+    precision: &SnapshotPrecision,
+) -> (Isometry<Real>, Vector2<Real>, Real) {
+    let position = scale(precision.position_bits);
+    let velocity = scale(precision.velocity_bits);
+    (
+        Isometry::new(
+            vector![values[0] as Real / position, values[1] as Real / position],
+            values[2] as Real / scale(precision.angle_bits) * 2.0 * PI,
+        ),
+        vector![values[3] as Real / velocity, values[4] as Real / velocity],
+        values[5] as Real / velocity,
+    )
+}
+
+fn scale(bits: u8) -> Real {
+    (1u32 << bits.min(MAX_BITS)) as Real
+}
+
+/// LEB128: seven bits per byte, the high bit is set on all bytes but the last.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Zigzag encoding, so that small negative values are short too.
+fn write_signed(bytes: &mut Vec<u8>, value: i64) {
+    write_varint(bytes, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if self.0.len() < len {
+            return Err(CodecError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, CodecError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, CodecError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    fn signed(&mut self) -> Result<i64, CodecError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn f32(&mut self) -> Result<f32, CodecError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(f32::from_le_bytes(bytes))
+    }
+}
@@ -0,0 +1,73 @@
+//! Collision events: what the physics step did to players, for game rules and effects.
+//!
+//! Rapier reports events in whatever order its solver finds them. The world translates them
+//! to [`CollisionEvent`]s and sorts them, so all processes handle them in the same order.
+
+use crate::{
+    arena::{PickupKind, ZoneKind},
+    PlayerId,
+};
+use rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CollisionEvent {
+    /// Two players started touching. The lower id comes first.
+    PlayersTouched(PlayerId, PlayerId),
+    /// Two players stopped touching. The lower id comes first.
+    PlayersSeparated(PlayerId, PlayerId),
+    /// A player started touching the arena's static shapes.
+    HitArena(PlayerId),
+    /// A player entered a zone. The last field is the zone's index in the arena's zones.
+    EnteredZone(PlayerId, ZoneKind, usize),
+    /// A player left a zone. The last field is the zone's index in the arena's zones.
+    LeftZone(PlayerId, ZoneKind, usize),
+    /// A player picked up a pickup. The last field is the pickup's index in the arena's pickups.
+    PickedUp(PlayerId, PickupKind, usize),
+    /// A player touched a hazard or left the arena, and was respawned.
+    KnockedOut(PlayerId),
+}
+
+impl CollisionEvent {
+    /// The players the event is about.
+    pub fn players(&self) -> Vec<PlayerId> {
+        match *self {
+            CollisionEvent::PlayersTouched(a, b) | CollisionEvent::PlayersSeparated(a, b) => {
+                vec![a, b]
+            }
+            CollisionEvent::HitArena(player_id)
+            | CollisionEvent::EnteredZone(player_id, _, _)
+            | CollisionEvent::LeftZone(player_id, _, _)
+            | CollisionEvent::PickedUp(player_id, _, _)
+            | CollisionEvent::KnockedOut(player_id) => vec![player_id],
+        }
+    }
+}
+
+/// Collects rapier's events during a physics step.
+/// Rapier may call the handler from several threads, hence the mutexes.
+#[derive(Default)]
+pub(crate) struct EventRecorder {
+    contacts: Mutex<Vec<ContactEvent>>,
+    intersections: Mutex<Vec<IntersectionEvent>>,
+}
+
+impl EventRecorder {
+    pub fn into_events(self) -> (Vec<ContactEvent>, Vec<IntersectionEvent>) {
+        (
+            self.contacts.into_inner().unwrap(),
+            self.intersections.into_inner().unwrap(),
+        )
+    }
+}
+
+impl EventHandler for EventRecorder {
+    fn handle_intersection_event(&self, event: IntersectionEvent) {
+        self.intersections.lock().unwrap().push(event);
+    }
+
+    fn handle_contact_event(&self, event: ContactEvent, _contact_pair: &ContactPair) {
+        self.contacts.lock().unwrap().push(event);
+    }
+}
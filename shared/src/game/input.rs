@@ -0,0 +1,95 @@
+//! Player input: analog steering and actions.
+//!
+//! Steering is quantized, so that all processes simulate exactly the same input
+//! no matter what device it came from. On the wire, a [`PlayerInput`] takes two bytes.
+
+use rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Quantized steering for full right. Full left is the negative.
+pub const FULL_STEERING: i8 = 127;
+
+const JUMP: u8 = 1;
+const DASH: u8 = 1 << 1;
+const BRAKE: u8 = 1 << 2;
+const GROUND_POUND: u8 = 1 << 3;
+
+/// What a player wants to do: the steering, and which actions are held. Part of the snapshot.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "[u8; 2]", into = "[u8; 2]")]
+pub struct PlayerInput {
+    /// Steering from `-FULL_STEERING` for full left to `FULL_STEERING` for full right.
+    pub steering: i8,
+    pub jump: bool,
+    pub dash: bool,
+    pub brake: bool,
+    pub ground_pound: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerAction {
+    Jump,
+    /// A burst of speed in the steering direction.
+    Dash,
+    /// Slow down while held.
+    Brake,
+    /// Drop down fast, only in the air.
+    GroundPound,
+}
+
+/// Quantize steering from -1.0 for full left to 1.0 for full right.
+pub fn quantize_steering(value: f32) -> i8 {
+    (value.max(-1.0).min(1.0) * FULL_STEERING as f32).round() as i8
+}
+
+impl PlayerInput {
+    /// Steering from -1.0 for full left to 1.0 for full right.
+    pub fn steering(&self) -> Real {
+        self.steering as Real / FULL_STEERING as Real
+    }
+
+    pub fn action(&self, action: PlayerAction) -> bool {
+        match action {
+            PlayerAction::Jump => self.jump,
+            PlayerAction::Dash => self.dash,
+            PlayerAction::Brake => self.brake,
+            PlayerAction::GroundPound => self.ground_pound,
+        }
+    }
+
+    pub fn set_action(&mut self, action: PlayerAction, pressed: bool) {
+        match action {
+            PlayerAction::Jump => self.jump = pressed,
+            PlayerAction::Dash => self.dash = pressed,
+            PlayerAction::Brake => self.brake = pressed,
+            PlayerAction::GroundPound => self.ground_pound = pressed,
+        }
+    }
+}
+
+impl From<PlayerInput> for [u8; 2] {
+    fn from(input: PlayerInput) -> Self {
+        let flags = [
+            (input.jump, JUMP),
+            (input.dash, DASH),
+            (input.brake, BRAKE),
+            (input.ground_pound, GROUND_POUND),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        [input.steering as u8, flags]
+    }
+}
+
+impl From<[u8; 2]> for PlayerInput {
+    fn from([steering, flags]: [u8; 2]) -> Self {
+        Self {
+            steering: (steering as i8).max(-FULL_STEERING),
+            jump: flags & JUMP != 0,
+            dash: flags & DASH != 0,
+            brake: flags & BRAKE != 0,
+            ground_pound: flags & GROUND_POUND != 0,
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Game modes: what players score points for.
+//!
+//! The mode's state is part of the simulated world, so it is included in snapshots.
+//! Modes only score while a round is being played, see [`crate::match_state`].
+
+use super::events::CollisionEvent;
+use crate::{match_state::seconds_to_ticks, PlayerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+/// How long it takes to score a point in the timed modes.
+const SECONDS_PER_POINT: f64 = 1.0;
+
+/// How long after a touch the toucher gets the credit for a knockout.
+const KNOCKOUT_CREDIT_SECONDS: f64 = 3.0;
+
+/// How long a player that just became "it" can't tag the player who tagged it.
+const TAG_IMMUNITY_SECONDS: f64 = 1.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GameModeKind {
+    /// Score for time spent alone in the hill zone.
+    KingOfTheHill,
+    /// Score for pushing other players into hazards or out of the arena.
+    Knockout,
+    /// One player is "it" and tags others by touching them. Score for time not being "it".
+    Tag,
+}
+
+/// What a mode gets to see and change in a step.
+pub struct StepContext<'a> {
+    /// All players, ordered by id.
+    pub players: &'a [PlayerId],
+    /// Players that are inside a hill zone, ordered by id.
+    pub in_hill: &'a [PlayerId],
+    /// What happened in the step. Ordered, so that all processes apply them the same way.
+    pub events: &'a [CollisionEvent],
+    pub scores: &'a mut BTreeMap<PlayerId, i32>,
+    /// Seconds per step.
+    pub timestep: f64,
+}
+
+/// The rules of a game mode.
+pub trait ModeRules {
+    fn round_started(&mut self, round: u32, players: &[PlayerId]);
+    fn player_removed(&mut self, player_id: PlayerId);
+    /// Called for each step while a round is being played.
+    fn step(&mut self, context: &mut StepContext);
+    /// A player that the mode singles out, such as the player who is "it" in tag.
+    fn marked_player(&self) -> Option<PlayerId> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GameMode {
+    KingOfTheHill(KingOfTheHill),
+    Knockout(Knockout),
+    Tag(Tag),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KingOfTheHill {
+    ticks_in_hill: BTreeMap<PlayerId, u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Knockout {
+    /// Who last touched each player, and how many ticks ago.
+    last_touched_by: BTreeMap<PlayerId, (PlayerId, u32)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Tag {
+    it: Option<PlayerId>,
+    /// The player who tagged the current "it", and how many more ticks it is immune.
+    immune: Option<(PlayerId, u32)>,
+    ticks_not_it: BTreeMap<PlayerId, u32>,
+}
+
+impl Default for GameModeKind {
+    fn default() -> Self {
+        GameModeKind::Tag
+    }
+}
+
+impl Display for GameModeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameModeKind::KingOfTheHill => write!(f, "King of the hill"),
+            GameModeKind::Knockout => write!(f, "Knockout"),
+            GameModeKind::Tag => write!(f, "Tag"),
+        }
+    }
+}
+
+impl GameMode {
+    pub fn new(kind: GameModeKind) -> Self {
+        match kind {
+            GameModeKind::KingOfTheHill => GameMode::KingOfTheHill(Default::default()),
+            GameModeKind::Knockout => GameMode::Knockout(Default::default()),
+            GameModeKind::Tag => GameMode::Tag(Default::default()),
+        }
+    }
+
+    pub fn kind(&self) -> GameModeKind {
+        match self {
+            GameMode::KingOfTheHill(_) => GameModeKind::KingOfTheHill,
+            GameMode::Knockout(_) => GameModeKind::Knockout,
+            GameMode::Tag(_) => GameModeKind::Tag,
+        }
+    }
+
+    pub fn rules(&self) -> &dyn ModeRules {
+        match self {
+            GameMode::KingOfTheHill(mode) => mode,
+            GameMode::Knockout(mode) => mode,
+            GameMode::Tag(mode) => mode,
+        }
+    }
+
+    pub fn rules_mut(&mut self) -> &mut dyn ModeRules {
+        match self {
+            GameMode::KingOfTheHill(mode) => mode,
+            GameMode::Knockout(mode) => mode,
+            GameMode::Tag(mode) => mode,
+        }
+    }
+}
+
+impl ModeRules for KingOfTheHill {
+    fn round_started(&mut self, _round: u32, _players: &[PlayerId]) {
+        self.ticks_in_hill.clear();
+    }
+
+    fn player_removed(&mut self, player_id: PlayerId) {
+        self.ticks_in_hill.remove(&player_id);
+    }
+
+    fn step(&mut self, context: &mut StepContext) {
+        // A contested hill scores for nobody
+        if let [king] = context.in_hill {
+            let ticks = self.ticks_in_hill.entry(*king).or_default();
+            *ticks += 1;
+            if *ticks >= seconds_to_ticks(SECONDS_PER_POINT, context.timestep) {
+                *ticks = 0;
+                *context.scores.entry(*king).or_default() += 1;
+            }
+        }
+    }
+}
+
+impl ModeRules for Knockout {
+    fn round_started(&mut self, _round: u32, _players: &[PlayerId]) {
+        self.last_touched_by.clear();
+    }
+
+    fn player_removed(&mut self, player_id: PlayerId) {
+        self.last_touched_by
+            .retain(|victim, (toucher, _)| *victim != player_id && *toucher != player_id);
+    }
+
+    fn step(&mut self, context: &mut StepContext) {
+        let credit_ticks = seconds_to_ticks(KNOCKOUT_CREDIT_SECONDS, context.timestep);
+        self.last_touched_by
+            .retain(|_, (_, age)| *age < credit_ticks);
+        for (_, age) in self.last_touched_by.values_mut() {
+            *age += 1;
+        }
+        for event in context.events {
+            match *event {
+                CollisionEvent::PlayersTouched(a, b) => {
+                    self.last_touched_by.insert(a, (b, 0));
+                    self.last_touched_by.insert(b, (a, 0));
+                }
+                CollisionEvent::KnockedOut(victim) => match self.last_touched_by.remove(&victim) {
+                    Some((toucher, _)) => *context.scores.entry(toucher).or_default() += 1,
+                    // Knocking yourself out costs a point
+                    None => *context.scores.entry(victim).or_default() -= 1,
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+impl ModeRules for Tag {
+    fn round_started(&mut self, round: u32, players: &[PlayerId]) {
+        self.it = match players.len() {
+            0 => None,
+            len => Some(players[round as usize % len]),
+        };
+        self.immune = None;
+        self.ticks_not_it.clear();
+    }
+
+    fn player_removed(&mut self, player_id: PlayerId) {
+        if self.it == Some(player_id) {
+            self.it = None;
+        }
+        if matches!(self.immune, Some((immune, _)) if immune == player_id) {
+            self.immune = None;
+        }
+        self.ticks_not_it.remove(&player_id);
+    }
+
+    fn step(&mut self, context: &mut StepContext) {
+        // Pick someone if "it" has left, or nobody was there when the round started
+        if self.it.is_none() {
+            self.it = context.players.first().copied();
+        }
+        self.immune = match self.immune {
+            Some((player_id, ticks)) if ticks > 1 => Some((player_id, ticks - 1)),
+            _ => None,
+        };
+        for event in context.events {
+            let (a, b) = match *event {
+                CollisionEvent::PlayersTouched(a, b) => (a, b),
+                _ => continue,
+            };
+            let (it, tagged) = match self.it {
+                Some(it) if it == a => (a, b),
+                Some(it) if it == b => (b, a),
+                _ => continue,
+            };
+            if matches!(self.immune, Some((immune, _)) if immune == tagged) {
+                continue;
+            }
+            self.it = Some(tagged);
+            self.immune = Some((it, seconds_to_ticks(TAG_IMMUNITY_SECONDS, context.timestep)));
+            // Only one tag per step, the new "it" has to touch someone again
+            break;
+        }
+        for player_id in context.players {
+            if Some(*player_id) == self.it {
+                continue;
+            }
+            let ticks = self.ticks_not_it.entry(*player_id).or_default();
+            *ticks += 1;
+            if *ticks >= seconds_to_ticks(SECONDS_PER_POINT, context.timestep) {
+                *ticks = 0;
+                *context.scores.entry(*player_id).or_default() += 1;
+            }
+        }
+    }
+
+    fn marked_player(&self) -> Option<PlayerId> {
+        self.it
+    }
+}
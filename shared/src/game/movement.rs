@@ -0,0 +1,209 @@
+//! How players move: steering, jumping from the ground or off walls, air control,
+//! dashing, braking and ground pounds.
+//!
+//! Whether a player touches the ground or a wall comes from the narrow phase's contacts.
+//! The timers for coyote time, jump buffering and the cooldowns count steps,
+//! and are part of the player's snapshot so that prediction stays consistent.
+
+use super::{
+    input::{PlayerAction, PlayerInput},
+    pickups::PlayerEffects,
+};
+use crate::match_state::seconds_to_ticks;
+use rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Tuning of the movement model.
+/// Only the server's tuning is used, clients get it with the world.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MovementTuning {
+    /// Horizontal force when steering on the ground.
+    pub steering_force: Real,
+    /// Part of the steering force that is available in the air, from 0 to 1.
+    pub air_control: Real,
+    pub jump_impulse: Real,
+    /// How long after leaving the ground a player can still jump.
+    pub coyote_seconds: f64,
+    /// How long a jump press is remembered, so jumping just before landing works.
+    pub jump_buffer_seconds: f64,
+    /// Minimum time between two jumps.
+    pub jump_cooldown_seconds: f64,
+    pub wall_jump: bool,
+    /// Impulse of a wall jump, away from the wall and up.
+    pub wall_jump_impulse: [Real; 2],
+    /// Contacts with a normal that points up at least this much count as ground, from 0 to 1.
+    /// Steeper contacts count as walls.
+    pub ground_normal_min_y: Real,
+    /// Horizontal impulse of a dash, in the steering direction.
+    pub dash_impulse: Real,
+    pub dash_cooldown_seconds: f64,
+    /// How fast braking slows a player down, as the part of its horizontal speed lost per second.
+    pub brake_rate: Real,
+    /// Downward impulse of a ground pound.
+    pub ground_pound_impulse: Real,
+}
+
+/// Movement state of a player.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct MovementState {
+    pub grounded: bool,
+    /// The side on which the player touches a wall: -1 for left, 1 for right, 0 for none.
+    pub wall: i8,
+    /// Steps since the player last touched the ground.
+    pub ticks_since_grounded: u32,
+    /// Steps left that a jump press is remembered.
+    pub jump_buffer_ticks: u32,
+    /// Steps left until the player can jump again.
+    pub jump_cooldown_ticks: u32,
+    /// Steps left until the player can dash again.
+    pub dash_cooldown_ticks: u32,
+    /// Whether the player has done a ground pound since it last touched the ground.
+    pub ground_pounded: bool,
+    /// The input of the last step, to find out which actions have just been pressed.
+    pub previous_input: PlayerInput,
+}
+
+impl Default for MovementTuning {
+    fn default() -> Self {
+        Self {
+            steering_force: 4000.0,
+            air_control: 0.3,
+            jump_impulse: 4000.0,
+            coyote_seconds: 0.1,
+            jump_buffer_seconds: 0.1,
+            jump_cooldown_seconds: 0.2,
+            wall_jump: true,
+            wall_jump_impulse: [3000.0, 3500.0],
+            ground_normal_min_y: 0.5,
+            dash_impulse: 3000.0,
+            dash_cooldown_seconds: 1.0,
+            brake_rate: 3.0,
+            ground_pound_impulse: 6000.0,
+        }
+    }
+}
+
+impl MovementState {
+    /// Update what the player touches from the contacts found in the last physics step.
+    pub fn update_contacts(
+        &mut self,
+        tuning: &MovementTuning,
+        narrow_phase: &NarrowPhase,
+        collider_handle: ColliderHandle,
+    ) {
+        self.grounded = false;
+        self.wall = 0;
+        if let Some(contacts) = narrow_phase.contacts_with(collider_handle) {
+            for (collider1, _, contact_pair) in contacts {
+                if !contact_pair.has_any_active_contact {
+                    continue;
+                }
+                for manifold in contact_pair.manifolds.iter() {
+                    if manifold.points.is_empty() {
+                        continue;
+                    }
+                    // The manifold's normal points away from the first collider.
+                    // Make it point from the player to what it touches.
+                    let normal = if collider1 == collider_handle {
+                        manifold.data.normal
+                    } else {
+                        -manifold.data.normal
+                    };
+                    if -normal.y >= tuning.ground_normal_min_y {
+                        self.grounded = true;
+                    } else if normal.x.abs() > normal.y.abs() {
+                        self.wall = normal.x.signum() as i8;
+                    }
+                }
+            }
+        }
+        self.ticks_since_grounded = if self.grounded {
+            self.ground_pounded = false;
+            0
+        } else {
+            self.ticks_since_grounded.saturating_add(1)
+        };
+    }
+
+    /// Apply steering and actions to the player's body.
+    /// Jumps, dashes and ground pounds happen when their action is pressed, not while it is held.
+    /// Consumes extra jumps in `effects`. The timestep is in seconds.
+    pub fn apply(
+        &mut self,
+        tuning: &MovementTuning,
+        input: &PlayerInput,
+        effects: &mut PlayerEffects,
+        body: &mut RigidBody,
+        timestep: f64,
+    ) {
+        let previous_input = self.previous_input;
+        self.previous_input = *input;
+        let pressed = |action| input.action(action) && !previous_input.action(action);
+
+        let control = if self.grounded {
+            1.0
+        } else {
+            tuning.air_control
+        };
+        body.apply_force(
+            vector![
+                input.steering() * tuning.steering_force * control * effects.force_factor(),
+                0.0
+            ],
+            true,
+        );
+        if input.brake {
+            let brake_force = -body.linvel().x * body.mass() * tuning.brake_rate;
+            body.apply_force(vector![brake_force, 0.0], true);
+        }
+
+        self.dash_cooldown_ticks = self.dash_cooldown_ticks.saturating_sub(1);
+        if pressed(PlayerAction::Dash) {
+            if self.dash_cooldown_ticks == 0 && input.steering != 0 {
+                let direction = (input.steering as Real).signum();
+                body.apply_impulse(vector![direction * tuning.dash_impulse, 0.0], true);
+                self.dash_cooldown_ticks = seconds_to_ticks(tuning.dash_cooldown_seconds, timestep);
+            }
+        }
+
+        if pressed(PlayerAction::GroundPound) {
+            if !self.grounded && !self.ground_pounded {
+                body.apply_impulse(vector![0.0, -tuning.ground_pound_impulse], true);
+                self.ground_pounded = true;
+            }
+        }
+
+        if pressed(PlayerAction::Jump) {
+            self.jump_buffer_ticks = seconds_to_ticks(tuning.jump_buffer_seconds, timestep).max(1);
+        }
+        self.jump_cooldown_ticks = self.jump_cooldown_ticks.saturating_sub(1);
+        if self.jump_buffer_ticks == 0 {
+            return;
+        }
+        self.jump_buffer_ticks -= 1;
+        if self.jump_cooldown_ticks > 0 {
+            return;
+        }
+
+        let impulse =
+            if self.ticks_since_grounded <= seconds_to_ticks(tuning.coyote_seconds, timestep) {
+                vector![0.0, tuning.jump_impulse]
+            } else if tuning.wall_jump && self.wall != 0 {
+                vector![
+                    -(self.wall as Real) * tuning.wall_jump_impulse[0],
+                    tuning.wall_jump_impulse[1]
+                ]
+            } else if effects.extra_jumps > 0 {
+                effects.extra_jumps -= 1;
+                vector![0.0, tuning.jump_impulse]
+            } else {
+                return;
+            };
+        body.apply_impulse(impulse, true);
+        self.jump_buffer_ticks = 0;
+        self.jump_cooldown_ticks = seconds_to_ticks(tuning.jump_cooldown_seconds, timestep);
+        // No second jump from the same coyote time
+        self.ticks_since_grounded = u32::MAX;
+    }
+}
@@ -0,0 +1,103 @@
+//! Pickups: collectibles in the arena that give players temporary effects.
+//!
+//! Which pickups are available, and the effects players have, are part of the snapshot,
+//! so that rollback restores them.
+
+use crate::{arena::PickupKind, match_state::seconds_to_ticks};
+use rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub const PICKUP_RADIUS: Real = 5.0;
+
+const SPEED_BOOST_SECONDS: f64 = 5.0;
+/// How much stronger steering is with a speed boost.
+const SPEED_BOOST_FACTOR: Real = 2.0;
+
+const HEAVY_SECONDS: f64 = 8.0;
+/// How much denser a player is with heavier mass.
+const HEAVY_DENSITY_FACTOR: Real = 4.0;
+
+/// Effects of pickups on a player.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct PlayerEffects {
+    pub speed_boost_ticks: u32,
+    pub heavy_ticks: u32,
+    /// Jumps the player can make without touching the ground.
+    pub extra_jumps: u8,
+}
+
+/// State of one of the arena's pickups.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct PickupState {
+    /// Steps until the pickup is back. Zero when it can be picked up.
+    pub respawn_ticks: u32,
+}
+
+/// A pickup that can be picked up, for rendering.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VisiblePickup {
+    /// Index in the arena's pickups.
+    pub index: usize,
+    pub kind: PickupKind,
+    pub position: [Real; 2],
+}
+
+impl PlayerEffects {
+    /// Give the player the effect of a pickup. The timestep is in seconds.
+    pub fn apply(&mut self, kind: PickupKind, timestep: f64) {
+        match kind {
+            PickupKind::SpeedBoost => {
+                self.speed_boost_ticks = seconds_to_ticks(SPEED_BOOST_SECONDS, timestep)
+            }
+            PickupKind::ExtraJump => self.extra_jumps = self.extra_jumps.saturating_add(1),
+            PickupKind::HeavierMass => self.heavy_ticks = seconds_to_ticks(HEAVY_SECONDS, timestep),
+        }
+    }
+
+    /// Count down the timed effects by one step.
+    pub fn step(&mut self) {
+        self.speed_boost_ticks = self.speed_boost_ticks.saturating_sub(1);
+        self.heavy_ticks = self.heavy_ticks.saturating_sub(1);
+    }
+
+    /// Factor for the steering force.
+    pub fn force_factor(&self) -> Real {
+        if self.speed_boost_ticks > 0 {
+            SPEED_BOOST_FACTOR
+        } else {
+            1.0
+        }
+    }
+
+    /// Factor for the player's density.
+    pub fn density_factor(&self) -> Real {
+        if self.heavy_ticks > 0 {
+            HEAVY_DENSITY_FACTOR
+        } else {
+            1.0
+        }
+    }
+}
+
+impl PickupState {
+    pub fn is_available(&self) -> bool {
+        self.respawn_ticks == 0
+    }
+
+    pub fn pick_up(&mut self, respawn_seconds: f64, timestep: f64) {
+        self.respawn_ticks = seconds_to_ticks(respawn_seconds, timestep).max(1);
+    }
+
+    /// Count down to respawning by one step. Returns true when the pickup has come back.
+    pub fn step(&mut self) -> bool {
+        if self.respawn_ticks == 0 {
+            return false;
+        }
+        self.respawn_ticks -= 1;
+        self.respawn_ticks == 0
+    }
+}
+
+pub fn pickup_collider() -> Collider {
+    ColliderBuilder::ball(PICKUP_RADIUS).sensor(true).build()
+}
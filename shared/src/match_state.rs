@@ -0,0 +1,165 @@
+//! Round-based match lifecycle: warmup, rounds, and an intermission before the next arena.
+//!
+//! The match state is part of the simulated world, and its timers count ticks,
+//! so clients predict phase changes the same way the server does.
+
+use crate::{arena::ArenaId, game::modes::GameModeKind};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// How long the phases of a match last.
+/// Only the server's settings are used, clients get them with the world.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchSettings {
+    pub mode: GameModeKind,
+    pub warmup_seconds: f64,
+    pub round_seconds: f64,
+    /// How long the end of a round is shown before the next round starts.
+    pub round_end_seconds: f64,
+    pub intermission_seconds: f64,
+    /// Number of rounds played in an arena before rotating to the next one.
+    pub rounds_per_arena: u32,
+    /// How many players can play at once. Other clients can still spectate.
+    pub max_players: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchPhase {
+    Warmup,
+    Playing,
+    RoundEnd,
+    Intermission,
+}
+
+/// Something that happened when the match state was stepped, that the world needs to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchEvent {
+    RoundStarted,
+    /// The intermission is over, continue in this arena.
+    ArenaChanged(ArenaId),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MatchState {
+    settings: MatchSettings,
+    /// The arenas to play, in order. Starts over after the last one.
+    rotation: Vec<ArenaId>,
+    rotation_index: usize,
+    phase: MatchPhase,
+    /// The current round, starting at 1. Zero during warmup.
+    round: u32,
+    ticks_left: u32,
+    /// Seconds per tick.
+    timestep: f64,
+}
+
+impl Default for MatchSettings {
+    fn default() -> Self {
+        Self {
+            mode: GameModeKind::default(),
+            warmup_seconds: 10.0,
+            round_seconds: 120.0,
+            round_end_seconds: 5.0,
+            intermission_seconds: 10.0,
+            rounds_per_arena: 3,
+            max_players: 16,
+        }
+    }
+}
+
+impl Display for MatchPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchPhase::Warmup => write!(f, "Warmup"),
+            MatchPhase::Playing => write!(f, "Playing"),
+            MatchPhase::RoundEnd => write!(f, "Round over"),
+            MatchPhase::Intermission => write!(f, "Intermission"),
+        }
+    }
+}
+
+impl MatchState {
+    /// Start a match with warmup in the first arena of the rotation.
+    /// An empty rotation stays in whatever arena the world is using.
+    pub fn new(settings: MatchSettings, rotation: Vec<ArenaId>, timestep: f64) -> Self {
+        let ticks_left = seconds_to_ticks(settings.warmup_seconds, timestep);
+        Self {
+            settings,
+            rotation,
+            rotation_index: 0,
+            phase: MatchPhase::Warmup,
+            round: 0,
+            ticks_left,
+            timestep,
+        }
+    }
+
+    pub fn phase(&self) -> MatchPhase {
+        self.phase
+    }
+
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    pub fn settings(&self) -> &MatchSettings {
+        &self.settings
+    }
+
+    /// The arena that should be played now, if there is a rotation.
+    pub fn arena(&self) -> Option<ArenaId> {
+        self.rotation.get(self.rotation_index).copied()
+    }
+
+    /// Time left in the current phase.
+    pub fn seconds_left(&self) -> f64 {
+        self.ticks_left as f64 * self.timestep
+    }
+
+    /// Advance the match by one tick.
+    pub fn step(&mut self) -> Option<MatchEvent> {
+        self.ticks_left = self.ticks_left.saturating_sub(1);
+        if self.ticks_left > 0 {
+            return None;
+        }
+        match self.phase {
+            MatchPhase::Warmup => Some(self.start_round(1)),
+            MatchPhase::Playing => {
+                self.enter(MatchPhase::RoundEnd, self.settings.round_end_seconds);
+                None
+            }
+            MatchPhase::RoundEnd => {
+                if self.round < self.settings.rounds_per_arena {
+                    Some(self.start_round(self.round + 1))
+                } else {
+                    self.enter(MatchPhase::Intermission, self.settings.intermission_seconds);
+                    None
+                }
+            }
+            MatchPhase::Intermission => {
+                if !self.rotation.is_empty() {
+                    self.rotation_index = (self.rotation_index + 1) % self.rotation.len();
+                }
+                self.round = 0;
+                self.enter(MatchPhase::Warmup, self.settings.warmup_seconds);
+                self.arena().map(MatchEvent::ArenaChanged)
+            }
+        }
+    }
+
+    fn start_round(&mut self, round: u32) -> MatchEvent {
+        self.round = round;
+        self.enter(MatchPhase::Playing, self.settings.round_seconds);
+        MatchEvent::RoundStarted
+    }
+
+    fn enter(&mut self, phase: MatchPhase, seconds: f64) {
+        self.phase = phase;
+        self.ticks_left = seconds_to_ticks(seconds, self.timestep);
+    }
+}
+
+pub(crate) fn seconds_to_ticks(seconds: f64, timestep: f64) -> u32 {
+    (seconds / timestep).round().max(0.0) as u32
+}
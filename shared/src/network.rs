@@ -0,0 +1,220 @@
+//! Network channel setup shared by server and client,
+//! and the handshake that makes sure that they are compatible.
+
+pub mod connection;
+
+use crate::{
+    config::Settings,
+    crystalorb_bevy_networking_turbulence::{
+        bevy_networking_turbulence::{
+            ConnectionChannelsBuilder, MessageChannelMode, MessageChannelSettings, NetworkResource,
+            ReliableChannelSettings,
+        },
+        ClockSyncChannelSettings, CommandChannelSettings, SnapshotChannelSettings,
+    },
+    game::{codec::SnapshotMessage, GameCommand},
+};
+use bevy::prelude::*;
+use crystalorb::{clocksync::ClockSyncMessage, timestamp::Timestamped};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Increase this whenever the serialized form of any message changes.
+pub const PROTOCOL_VERSION: u32 = 20;
+
+/// Label of the startup system that registers the network channels.
+/// Connections that are opened before this system has run will not have the handshake channel.
+pub const REGISTER_CHANNELS: &str = "register_channels";
+
+/// The channel used for [`HandshakeMessage`]. Its settings never change,
+/// so that the handshake works even if the other channels are configured differently.
+const HANDSHAKE_CHANNEL: u8 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum HandshakeMessage {
+    /// Sent by the client when it has connected.
+    /// The server does not spawn a player for the client until it has accepted this.
+    Hello {
+        protocol_version: u32,
+        channels: u64,
+        world: u64,
+        /// How many players the client wants to control. Zero to spectate.
+        local_players: u8,
+    },
+    /// Sent by the server when the client is compatible. The client gets the arenas that the
+    /// server plays and the rules with the world, so it does not need to have them.
+    Welcome,
+    /// Sent by the server to a client that is not compatible, before disconnecting it.
+    Rejected { reason: String },
+    /// Sent by an accepted client that wants to stop spectating and play.
+    /// The server ignores it if there is no room for the players.
+    Join { local_players: u8 },
+    /// Sent by an accepted client that wants to stop playing and spectate.
+    Spectate,
+    /// Sent by an accepted client or by the server to measure the round trip time.
+    /// The time is the sender's, in seconds since it started.
+    Ping { sent_at: f64 },
+    /// The answer to [`HandshakeMessage::Ping`], with the same time.
+    Pong { sent_at: f64 },
+    /// Sent by an accepted client with the snapshots it has received since it last sent this,
+    /// so that the server can delta-encode snapshots against them, see [`crate::game::codec`].
+    Acknowledge { snapshots: Vec<u32> },
+}
+
+/// Hashes of the settings that must be the same on server and clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Hash of the network channel settings.
+    pub channels: u64,
+    /// Hash of the settings that affect the simulation.
+    pub world: u64,
+}
+
+/// Installs the channel settings from [`Settings`] and registers the handshake channel.
+/// Add this plugin on both server and client so they are guaranteed to use the same settings.
+pub struct NetworkSettingsPlugin {
+    command: CommandChannelSettings,
+    snapshot: SnapshotChannelSettings,
+    clock_sync: ClockSyncChannelSettings,
+    fingerprint: Fingerprint,
+}
+
+impl NetworkSettingsPlugin {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            command: settings.command_channel_settings(),
+            snapshot: settings.snapshot_channel_settings(),
+            clock_sync: settings.clock_sync_channel_settings(),
+            fingerprint: Fingerprint {
+                channels: fnv1a(
+                    format!(
+                        "{:?} {:?} {:?}",
+                        settings.command_channel,
+                        settings.snapshot_channel,
+                        settings.clock_sync_channel
+                    )
+                    .as_bytes(),
+                ),
+                world: fnv1a(
+                    format!(
+                        "{:?} {:?}",
+                        settings.timestep.to_bits(),
+                        settings.crystalorb
+                    )
+                    .as_bytes(),
+                ),
+            },
+        }
+    }
+}
+
+impl Plugin for NetworkSettingsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(CommandChannelSettings(self.command.0.clone()))
+            .insert_resource(SnapshotChannelSettings(self.snapshot.0.clone()))
+            .insert_resource(ClockSyncChannelSettings(self.clock_sync.0.clone()))
+            .insert_resource(self.fingerprint)
+            // Runs after crystalorb's plugin has registered its channels, and replaces them.
+            .add_startup_system_to_stage(
+                StartupStage::PostStartup,
+                register_channels.system().label(REGISTER_CHANNELS),
+            );
+    }
+}
+
+impl HandshakeMessage {
+    pub fn hello(fingerprint: Fingerprint, local_players: u8) -> Self {
+        HandshakeMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            channels: fingerprint.channels,
+            world: fingerprint.world,
+            local_players,
+        }
+    }
+
+    /// Check a [`HandshakeMessage::Hello`] from a client.
+    /// Returns how many players the client wants to control,
+    /// or the reason for rejecting the client if it is not compatible.
+    pub fn check_hello(&self, fingerprint: Fingerprint) -> Result<u8, String> {
+        match self {
+            HandshakeMessage::Hello {
+                protocol_version,
+                channels,
+                world,
+                local_players,
+            } => {
+                if *protocol_version != PROTOCOL_VERSION {
+                    Err(format!(
+                        "Protocol version {} is not supported, the server uses version {}",
+                        protocol_version, PROTOCOL_VERSION
+                    ))
+                } else if *channels != fingerprint.channels {
+                    Err("Network channel settings differ from the server's".to_string())
+                } else if *world != fingerprint.world {
+                    Err("Simulation settings differ from the server's".to_string())
+                } else {
+                    Ok(*local_players)
+                }
+            }
+            _ => Err(format!("Expected hello, got {:?}", self)),
+        }
+    }
+}
+
+fn register_channels(
+    mut net: ResMut<NetworkResource>,
+    command: Res<CommandChannelSettings>,
+    snapshot: Res<SnapshotChannelSettings>,
+    clock_sync: Res<ClockSyncChannelSettings>,
+) {
+    let command = command.0.clone();
+    let snapshot = snapshot.0.clone();
+    let clock_sync = clock_sync.0.clone();
+    net.set_channels_builder(move |builder: &mut ConnectionChannelsBuilder| {
+        builder
+            .register::<Timestamped<GameCommand>>(command.clone())
+            .unwrap();
+        builder
+            .register::<SnapshotMessage>(snapshot.clone())
+            .unwrap();
+        builder
+            .register::<ClockSyncMessage>(clock_sync.clone())
+            .unwrap();
+        builder
+            .register::<HandshakeMessage>(handshake_channel_settings())
+            .unwrap();
+    });
+}
+
+fn handshake_channel_settings() -> MessageChannelSettings {
+    MessageChannelSettings {
+        channel: HANDSHAKE_CHANNEL,
+        channel_mode: MessageChannelMode::Compressed {
+            reliability_settings: ReliableChannelSettings {
+                bandwidth: 4096,
+                recv_window_size: 1024,
+                send_window_size: 1024,
+                burst_bandwidth: 1024,
+                init_send: 512,
+                wakeup_time: Duration::from_millis(100),
+                initial_rtt: Duration::from_millis(200),
+                max_rtt: Duration::from_secs(2),
+                rtt_update_factor: 0.1,
+                rtt_resend_factor: 1.5,
+            },
+            max_chunk_len: 1024,
+        },
+        message_buffer_size: 8,
+        packet_buffer_size: 8,
+    }
+}
+
+/// 64-bit FNV-1a hash. Unlike `DefaultHasher`, the result is the same for all builds.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
@@ -0,0 +1,357 @@
+//! The network that crystalorb's server and clients are updated with.
+//!
+//! Crystalorb sends the same [`GameSnapshot`] to every client. [`GameNetwork`] encodes it for
+//! each connection on the way out, against the newest snapshot that the client has acknowledged,
+//! and decodes it on the way in, see [`crate::game::codec`]. The messages go over a
+//! [`Transport`]: turbulence's connections in the server and the client, or the simulated ones in
+//! tests. The messages on the command, snapshot and handshake channels are counted in
+//! [`NetworkStats`] as they are sent and received. The server drops the commands that a client
+//! may not issue as they arrive.
+
+use super::HandshakeMessage;
+use crate::{
+    crystalorb_bevy_networking_turbulence::bevy_networking_turbulence::{
+        ConnectionHandle, MessageChannels, NetworkResource as TurbulenceResource,
+    },
+    game::{
+        codec::{ReceivedSnapshots, SentSnapshots, SnapshotMessage},
+        GameCommand, GameSnapshot, GameWorld,
+    },
+    stats::{Channel, NetworkStats},
+};
+use bevy::prelude::*;
+use crystalorb::{
+    clocksync::ClockSyncMessage,
+    network_resource::{Connection, NetworkResource},
+    timestamp::Timestamped,
+    world::World,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    fmt::Debug,
+};
+
+/// Anything that can be sent on a channel.
+pub trait Message: Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static {}
+
+impl<T: Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static> Message for T {}
+
+/// The channels of one connection, one for each type of message.
+pub trait Channels {
+    /// Queue a message. Returns it if the channel is full.
+    fn send<M: Message>(&mut self, message: M) -> Option<M>;
+    fn recv<M: Message>(&mut self) -> Option<M>;
+    /// Send the queued messages of a type.
+    fn flush<M: Message>(&mut self);
+}
+
+/// The connections that [`GameNetwork`] sends messages over.
+pub trait Transport {
+    type Channels: Channels;
+
+    fn channels(&mut self, handle: ConnectionHandle) -> Option<&mut Self::Channels>;
+
+    fn all_channels(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = (ConnectionHandle, &mut Self::Channels)> + '_>;
+}
+
+impl Channels for MessageChannels {
+    fn send<M: Message>(&mut self, message: M) -> Option<M> {
+        MessageChannels::send(self, message)
+    }
+
+    fn recv<M: Message>(&mut self) -> Option<M> {
+        MessageChannels::recv(self)
+    }
+
+    fn flush<M: Message>(&mut self) {
+        MessageChannels::flush::<M>(self)
+    }
+}
+
+impl Transport for TurbulenceResource {
+    type Channels = MessageChannels;
+
+    fn channels(&mut self, handle: ConnectionHandle) -> Option<&mut MessageChannels> {
+        self.connections.get_mut(&handle)?.channels()
+    }
+
+    fn all_channels(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = (ConnectionHandle, &mut MessageChannels)> + '_> {
+        Box::new(
+            self.connections
+                .iter_mut()
+                .filter_map(|(handle, connection)| Some((*handle, connection.channels()?))),
+        )
+    }
+}
+
+/// Which end of the connections this is, with what it keeps of the snapshots.
+enum Side<'a> {
+    Server(&'a mut SentSnapshots),
+    Client(&'a mut ReceivedSnapshots),
+}
+
+/// What the connections of a [`GameNetwork`] share.
+struct Shared<'a> {
+    side: Side<'a>,
+    stats: &'a mut NetworkStats,
+    /// When the messages are received, in seconds since startup, if the clock offset is measured.
+    received_at: Option<f64>,
+}
+
+/// A [`Transport`] that encodes and decodes the snapshots, for crystalorb.
+pub struct GameNetwork<'a, T: Transport> {
+    transport: &'a mut T,
+    shared: RefCell<Shared<'a>>,
+}
+
+/// One connection of a [`GameNetwork`].
+pub struct GameConnection<'b, 'a, C: Channels> {
+    handle: ConnectionHandle,
+    channels: &'b mut C,
+    shared: &'b RefCell<Shared<'a>>,
+}
+
+impl<'a, T: Transport> GameNetwork<'a, T> {
+    pub fn server(
+        transport: &'a mut T,
+        sent: &'a mut SentSnapshots,
+        stats: &'a mut NetworkStats,
+    ) -> Self {
+        Self::new(transport, Side::Server(sent), stats)
+    }
+
+    pub fn client(
+        transport: &'a mut T,
+        received: &'a mut ReceivedSnapshots,
+        stats: &'a mut NetworkStats,
+    ) -> Self {
+        Self::new(transport, Side::Client(received), stats)
+    }
+
+    fn new(transport: &'a mut T, side: Side<'a>, stats: &'a mut NetworkStats) -> Self {
+        Self {
+            transport,
+            shared: RefCell::new(Shared {
+                side,
+                stats,
+                received_at: None,
+            }),
+        }
+    }
+
+    /// Measure the clock offset of the client with the clock sync messages that arrive now.
+    /// Crystalorb only receives them when it is updated.
+    pub fn received_at(self, seconds_since_startup: f64) -> Self {
+        self.shared.borrow_mut().received_at = Some(seconds_since_startup);
+        self
+    }
+
+    /// The connection with this handle, if it is open.
+    pub fn connection(
+        &mut self,
+        handle: ConnectionHandle,
+    ) -> Option<GameConnection<'_, 'a, T::Channels>> {
+        let shared = &self.shared;
+        self.transport
+            .channels(handle)
+            .map(|channels| GameConnection {
+                handle,
+                channels,
+                shared,
+            })
+    }
+
+    /// Take the handshake messages that have arrived on all connections.
+    pub fn recv_handshakes(&mut self) -> Vec<(ConnectionHandle, HandshakeMessage)> {
+        let mut messages = Vec::new();
+        for (_, mut connection) in self.connections() {
+            while let Some(message) = connection.recv_handshake() {
+                messages.push((connection.handle(), message));
+            }
+        }
+        messages
+    }
+
+    /// Send a handshake message right away. Returns it if it can't be sent.
+    pub fn send_handshake(
+        &mut self,
+        handle: ConnectionHandle,
+        message: HandshakeMessage,
+    ) -> Option<HandshakeMessage> {
+        match self.connection(handle) {
+            Some(mut connection) => {
+                let unsent = connection.send(message);
+                connection.flush::<HandshakeMessage>();
+                unsent
+            }
+            None => Some(message),
+        }
+    }
+}
+
+impl<'a, T: Transport> NetworkResource<GameWorld> for GameNetwork<'a, T> {
+    type ConnectionType<'b>
+    where
+        'a: 'b,
+    = GameConnection<'b, 'a, T::Channels>;
+
+    fn get_connection(&mut self, handle: usize) -> Option<Self::ConnectionType<'_>> {
+        self.connection(handle as ConnectionHandle)
+    }
+
+    fn connections<'b>(
+        &'b mut self,
+    ) -> Box<dyn Iterator<Item = (usize, Self::ConnectionType<'b>)> + 'b> {
+        let shared = &self.shared;
+        Box::new(
+            self.transport
+                .all_channels()
+                .map(move |(handle, channels)| {
+                    (
+                        handle as usize,
+                        GameConnection {
+                            handle,
+                            channels,
+                            shared,
+                        },
+                    )
+                }),
+        )
+    }
+}
+
+impl<'b, 'a, C: Channels> GameConnection<'b, 'a, C> {
+    pub fn handle(&self) -> ConnectionHandle {
+        self.handle
+    }
+
+    pub fn recv_handshake(&mut self) -> Option<HandshakeMessage> {
+        self.recv_counted()
+    }
+
+    fn recv_counted<M: Message>(&mut self) -> Option<M> {
+        let message = self.channels.recv::<M>()?;
+        if let Some(channel) = counted_channel::<M>() {
+            let size = message_size(&message);
+            let mut shared = self.shared.borrow_mut();
+            shared.stats.record_received(self.handle, channel, size);
+        }
+        Some(message)
+    }
+
+    fn send_counted<M: Message>(&mut self, message: M) -> Option<M> {
+        let channel = counted_channel::<M>();
+        let size = message_size(&message);
+        let unsent = self.channels.send(message);
+        if let (None, Some(channel)) = (&unsent, channel) {
+            let mut shared = self.shared.borrow_mut();
+            shared.stats.record_sent(self.handle, channel, size);
+        }
+        unsent
+    }
+}
+
+impl<'b, 'a, C: Channels> Connection<GameWorld> for GameConnection<'b, 'a, C> {
+    fn recv_command(&mut self) -> Option<Timestamped<GameCommand>> {
+        while let Some(command) = self.recv_counted::<Timestamped<GameCommand>>() {
+            let mut shared = self.shared.borrow_mut();
+            if let Side::Server(_) = shared.side {
+                if !GameWorld::command_is_valid(command.inner(), self.handle as usize) {
+                    debug!(
+                        "Rejecting command from client {}: {:?}",
+                        self.handle, command
+                    );
+                    shared.stats.record_rejected_command(self.handle);
+                    continue;
+                }
+            }
+            return Some(command);
+        }
+        None
+    }
+
+    fn recv_snapshot(&mut self) -> Option<Timestamped<GameSnapshot>> {
+        while let Some(message) = self.recv_counted::<SnapshotMessage>() {
+            let decoded = match &mut self.shared.borrow_mut().side {
+                Side::Client(received) => received.decode(&message),
+                Side::Server(_) => {
+                    warn!("Ignoring snapshot from client {}", self.handle);
+                    continue;
+                }
+            };
+            match decoded {
+                Ok(snapshot) => return Some(snapshot),
+                // The next snapshot that is not encoded against a lost one will do
+                Err(error) => debug!("Dropping snapshot {}: {}", message.id, error),
+            }
+        }
+        None
+    }
+
+    fn recv_clock_sync(&mut self) -> Option<ClockSyncMessage> {
+        let message = self.channels.recv::<ClockSyncMessage>()?;
+        let mut shared = self.shared.borrow_mut();
+        if let (Side::Client(_), Some(now)) = (&shared.side, shared.received_at) {
+            // As crystalorb measures it: the server's time against the middle of the round trip
+            let offset = message.server_seconds_since_startup
+                - (message.client_send_seconds_since_startup + now) / 2.0;
+            shared.stats.record_clock_offset(self.handle, offset);
+        }
+        Some(message)
+    }
+
+    fn send<M>(&mut self, message: M) -> Option<M>
+    where
+        M: Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let snapshot = match (&message as &dyn Any).downcast_ref::<Timestamped<GameSnapshot>>() {
+            Some(snapshot) => snapshot,
+            None => return self.send_counted(message),
+        };
+        let encoded = match &mut self.shared.borrow_mut().side {
+            Side::Server(sent) => sent.encode(self.handle, snapshot),
+            Side::Client(_) => {
+                warn!("Clients do not send snapshots");
+                return Some(message);
+            }
+        };
+        self.send_counted(encoded).map(|_| message)
+    }
+
+    fn flush<M>(&mut self)
+    where
+        M: Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        // Snapshots are sent encoded, see send
+        if TypeId::of::<M>() == TypeId::of::<Timestamped<GameSnapshot>>() {
+            self.channels.flush::<SnapshotMessage>();
+        } else {
+            self.channels.flush::<M>();
+        }
+    }
+}
+
+/// The channel that messages of a type are counted on. Clock sync messages are not counted.
+fn counted_channel<M: 'static>() -> Option<Channel> {
+    let type_id = TypeId::of::<M>();
+    if type_id == TypeId::of::<Timestamped<GameCommand>>() {
+        Some(Channel::Command)
+    } else if type_id == TypeId::of::<SnapshotMessage>() {
+        Some(Channel::Snapshot)
+    } else if type_id == TypeId::of::<HandshakeMessage>() {
+        Some(Channel::Handshake)
+    } else {
+        None
+    }
+}
+
+/// The size of a message as it is serialized on a channel, without the channel's overhead.
+fn message_size<M: Serialize>(message: &M) -> u64 {
+    bincode::serialized_size(message).unwrap_or(0)
+}
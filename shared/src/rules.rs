@@ -0,0 +1,37 @@
+//! Game rules: the physics constants and movement tuning that worlds are simulated with.
+//!
+//! The server chooses the rules and sends them to clients with the world,
+//! so they can be tuned without rebuilding server or client.
+
+use crate::game::movement::MovementTuning;
+use rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameRules {
+    /// Downward acceleration.
+    pub gravity: Real,
+    pub player_radius: Real,
+    pub player_density: Real,
+    pub player_restitution: Real,
+    pub movement: MovementTuning,
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        Self {
+            gravity: 9.81 * 30.0,
+            player_radius: 10.0,
+            player_density: 0.1,
+            player_restitution: 0.5,
+            movement: Default::default(),
+        }
+    }
+}
+
+impl GameRules {
+    pub fn gravity_vector(&self) -> Vector<Real> {
+        vector![0.0, -self.gravity]
+    }
+}
@@ -0,0 +1,476 @@
+//! Headless client/server simulation for tests.
+//!
+//! Runs a [`Server`] and several [`Client`]s over a simulated network, with simulated time,
+//! so game logic and netcode can be checked without opening any windows. The messages go through
+//! [`GameNetwork`] and are serialized with bincode like on turbulence's channels, so snapshots are
+//! encoded and acknowledged as they are between the real server and clients.
+
+use crate::{
+    crystalorb_bevy_networking_turbulence::bevy_networking_turbulence::ConnectionHandle,
+    game::{
+        codec::{ReceivedSnapshots, SentSnapshots, SnapshotPrecision},
+        ClientHandle, GameCommand, GameDisplayState, GameWorld, PlayerInput,
+    },
+    network::{
+        connection::{Channels, GameNetwork, Message, Transport},
+        HandshakeMessage,
+    },
+    stats::NetworkStats,
+};
+use crystalorb::{
+    client::{
+        stage::{Stage, StageMut},
+        Client,
+    },
+    server::Server,
+    timestamp::Timestamped,
+    Config,
+};
+use rapier2d::prelude::Real;
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, HashMap, VecDeque},
+};
+
+/// Number of clients that [`Simulation::new`] connects to the server.
+pub const CLIENT_COUNT: usize = 2;
+
+/// How long it takes to send a lost message on a reliable channel again, in seconds.
+const RESEND_DELAY: f64 = 0.1;
+
+/// The handle of the server's connection on a client.
+const SERVER_HANDLE: ConnectionHandle = 0;
+
+/// One end of a simulated connection.
+#[derive(Default)]
+pub struct SimulatedChannels {
+    /// Messages sent since the simulation last moved them onto the link.
+    outgoing: Vec<(TypeId, Vec<u8>)>,
+    /// Messages that have arrived, by type.
+    incoming: HashMap<TypeId, VecDeque<Vec<u8>>>,
+}
+
+/// The connections of the server, or of a client.
+#[derive(Default)]
+pub struct SimulatedNetwork {
+    connections: BTreeMap<ConnectionHandle, SimulatedChannels>,
+}
+
+/// A message on its way between a client and the server.
+struct Packet {
+    type_id: TypeId,
+    bytes: Vec<u8>,
+    arrives_at: f64,
+}
+
+/// The messages on their way between a client and the server, in one direction.
+#[derive(Default)]
+struct Path {
+    packets: Vec<Packet>,
+    /// When the last message of each reliable type arrives. Reliable channels keep their order.
+    last_arrival: HashMap<TypeId, f64>,
+}
+
+/// The simulated network between a client and the server.
+#[derive(Default)]
+struct Link {
+    joined: bool,
+    /// The fraction of messages that are lost, see [`Simulation::set_packet_loss`].
+    loss: f64,
+    to_server: Path,
+    to_client: Path,
+}
+
+/// Decides which messages are lost. Seeded, so that every run loses the same messages.
+struct Random(u64);
+
+pub struct Simulation {
+    pub server: Server<GameWorld>,
+    pub clients: Vec<Client<GameWorld>>,
+    server_net: SimulatedNetwork,
+    sent: SentSnapshots,
+    server_stats: NetworkStats,
+    client_nets: Vec<SimulatedNetwork>,
+    received: Vec<ReceivedSnapshots>,
+    client_stats: Vec<NetworkStats>,
+    links: Vec<Link>,
+    /// Whether each client has been given a player by [`Simulation::spawn_players`].
+    spawned: Vec<bool>,
+    /// One-way latency in seconds, see [`Simulation::set_latency`].
+    latency: f64,
+    random: Random,
+    clock: f64,
+}
+
+impl Channels for SimulatedChannels {
+    fn send<M: Message>(&mut self, message: M) -> Option<M> {
+        let bytes = bincode::serialize(&message).expect("messages can always be serialized");
+        self.outgoing.push((TypeId::of::<M>(), bytes));
+        None
+    }
+
+    fn recv<M: Message>(&mut self) -> Option<M> {
+        let bytes = self.incoming.get_mut(&TypeId::of::<M>())?.pop_front()?;
+        Some(bincode::deserialize(&bytes).expect("a message does not deserialize as it was sent"))
+    }
+
+    /// Messages are put on the link when the simulation is updated.
+    fn flush<M: Message>(&mut self) {}
+}
+
+impl Transport for SimulatedNetwork {
+    type Channels = SimulatedChannels;
+
+    fn channels(&mut self, handle: ConnectionHandle) -> Option<&mut SimulatedChannels> {
+        self.connections.get_mut(&handle)
+    }
+
+    fn all_channels(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = (ConnectionHandle, &mut SimulatedChannels)> + '_> {
+        Box::new(
+            self.connections
+                .iter_mut()
+                .map(|(handle, channels)| (*handle, channels)),
+        )
+    }
+}
+
+impl Path {
+    /// Put the messages on the path. Lost messages on unreliable channels are dropped, and lost
+    /// messages on reliable channels arrive late.
+    fn send(
+        &mut self,
+        messages: Vec<(TypeId, Vec<u8>)>,
+        arrives_at: f64,
+        loss: f64,
+        random: &mut Random,
+    ) {
+        for (type_id, bytes) in messages {
+            let mut arrives_at = arrives_at;
+            if is_reliable(type_id) {
+                while random.next_f64() < loss {
+                    arrives_at += RESEND_DELAY;
+                }
+                let last_arrival = self.last_arrival.entry(type_id).or_insert(arrives_at);
+                arrives_at = arrives_at.max(*last_arrival);
+                *last_arrival = arrives_at;
+            } else if random.next_f64() < loss {
+                continue;
+            }
+            self.packets.push(Packet {
+                type_id,
+                bytes,
+                arrives_at,
+            });
+        }
+    }
+
+    /// Hand the messages that have arrived by `now` to the receiving end, in the order they
+    /// were sent.
+    fn deliver(&mut self, now: f64, channels: &mut SimulatedChannels) {
+        let (mut arrived, on_the_way): (Vec<Packet>, Vec<Packet>) = self
+            .packets
+            .drain(..)
+            .partition(|packet| packet.arrives_at <= now);
+        self.packets = on_the_way;
+        arrived.sort_by(|a, b| a.arrives_at.partial_cmp(&b.arrives_at).unwrap());
+        for packet in arrived {
+            channels
+                .incoming
+                .entry(packet.type_id)
+                .or_default()
+                .push_back(packet.bytes);
+        }
+    }
+}
+
+/// Whether messages of a type go on a reliable channel, as registered by
+/// [`crate::network::NetworkSettingsPlugin`] with the default settings.
+fn is_reliable(type_id: TypeId) -> bool {
+    type_id == TypeId::of::<Timestamped<GameCommand>>()
+        || type_id == TypeId::of::<HandshakeMessage>()
+}
+
+impl Random {
+    /// A number in `0.0..1.0`, from xorshift64*.
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let bits = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        bits as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Simulation {
+    /// A simulation with [`CLIENT_COUNT`] clients that have all joined.
+    pub fn new(config: Config) -> Self {
+        let mut simulation = Self::with_clients(config, CLIENT_COUNT);
+        for client_index in 0..CLIENT_COUNT {
+            simulation.join(client_index);
+        }
+        simulation
+    }
+
+    /// A simulation with `client_count` clients that have not joined yet.
+    pub fn with_clients(config: Config, client_count: usize) -> Self {
+        Self {
+            server: Server::new(config.clone(), 0.0),
+            clients: (0..client_count)
+                .map(|_| Client::new(config.clone()))
+                .collect(),
+            server_net: SimulatedNetwork::default(),
+            sent: SentSnapshots::new(SnapshotPrecision::default()),
+            server_stats: NetworkStats::default(),
+            client_nets: (0..client_count).map(|_| Default::default()).collect(),
+            received: (0..client_count).map(|_| Default::default()).collect(),
+            client_stats: (0..client_count).map(|_| Default::default()).collect(),
+            links: (0..client_count).map(|_| Default::default()).collect(),
+            spawned: vec![false; client_count],
+            latency: 0.0,
+            random: Random(0x9e37_79b9_7f4a_7c15),
+            clock: 0.0,
+        }
+    }
+
+    /// Connect a client to the server. The server's handle of the connection is the client's
+    /// index.
+    pub fn join(&mut self, client_index: usize) {
+        self.links[client_index].joined = true;
+        self.server_net
+            .connections
+            .insert(client_index as ConnectionHandle, Default::default());
+        self.client_nets[client_index]
+            .connections
+            .insert(SERVER_HANDLE, Default::default());
+    }
+
+    /// Simulated time in seconds since the simulation started.
+    pub fn clock(&self) -> f64 {
+        self.clock
+    }
+
+    /// Set the one-way latency in seconds for all traffic.
+    pub fn set_latency(&mut self, seconds: f64) {
+        self.latency = seconds;
+    }
+
+    /// Set the fraction of messages between a client and the server that are lost, in both
+    /// directions. Lost messages on reliable channels are sent again after a while.
+    pub fn set_packet_loss(&mut self, client_index: usize, loss: f64) {
+        self.links[client_index].loss = loss;
+    }
+
+    /// What the server has counted on its connections.
+    pub fn server_stats(&self) -> &NetworkStats {
+        &self.server_stats
+    }
+
+    /// Advance the simulation by `delta_seconds`.
+    pub fn update(&mut self, delta_seconds: f64) {
+        self.clock += delta_seconds;
+        self.deliver();
+        self.handle_acknowledgements();
+        self.server.update(
+            delta_seconds,
+            self.clock,
+            &mut GameNetwork::server(&mut self.server_net, &mut self.sent, &mut self.server_stats),
+        );
+        for client_index in 0..self.clients.len() {
+            let mut network = GameNetwork::client(
+                &mut self.client_nets[client_index],
+                &mut self.received[client_index],
+                &mut self.client_stats[client_index],
+            )
+            .received_at(self.clock);
+            self.clients[client_index].update(delta_seconds, self.clock, &mut network);
+            self.acknowledge_snapshots(client_index);
+        }
+        self.transmit();
+    }
+
+    /// Advance the simulation by `seconds`, in steps of `delta_seconds`.
+    pub fn run_for(&mut self, seconds: f64, delta_seconds: f64) {
+        let end = self.clock + seconds;
+        while self.clock < end {
+            self.update(delta_seconds);
+        }
+    }
+
+    /// Advance the simulation until all clients are ready, or until `timeout` seconds have passed.
+    /// Returns whether all clients became ready.
+    pub fn run_until_ready(&mut self, timeout: f64, delta_seconds: f64) -> bool {
+        let end = self.clock + timeout;
+        while self.clock < end {
+            if self.all_clients_ready() {
+                return true;
+            }
+            self.update(delta_seconds);
+        }
+        self.all_clients_ready()
+    }
+
+    pub fn all_clients_ready(&self) -> bool {
+        self.clients
+            .iter()
+            .all(|client| matches!(client.stage(), Stage::Ready(_)))
+    }
+
+    /// Issue a [`GameCommand::SpawnPlayer`] for every ready client that does not have a player yet,
+    /// like the server does on connect.
+    pub fn spawn_players(&mut self) {
+        let mut handles = Vec::new();
+        for (client, spawned) in self.clients.iter().zip(self.spawned.iter_mut()) {
+            if let Stage::Ready(ready_client) = client.stage() {
+                if !*spawned {
+                    *spawned = true;
+                    handles.push(ready_client.client_id() as ClientHandle);
+                }
+            }
+        }
+        for client_handle in handles {
+            self.server.issue_command(
+                GameCommand::SpawnPlayer {
+                    client_handle,
+                    local_players: 1,
+                },
+                &mut GameNetwork::server(
+                    &mut self.server_net,
+                    &mut self.sent,
+                    &mut self.server_stats,
+                ),
+            );
+        }
+    }
+
+    /// Set the input of a client's first player.
+    /// Returns false if the client is not ready.
+    pub fn issue_input(&mut self, client_index: usize, input: PlayerInput) -> bool {
+        let client_handle = match self.clients[client_index].stage() {
+            Stage::Ready(ready_client) => ready_client.client_id() as ClientHandle,
+            _ => return false,
+        };
+        self.issue_command(client_index, GameCommand::SetInput(client_handle, 0, input))
+    }
+
+    /// Issue a command from a client, as if it was its own.
+    /// Returns false if the client is not ready.
+    pub fn issue_command(&mut self, client_index: usize, command: GameCommand) -> bool {
+        let mut network = GameNetwork::client(
+            &mut self.client_nets[client_index],
+            &mut self.received[client_index],
+            &mut self.client_stats[client_index],
+        );
+        match self.clients[client_index].stage_mut() {
+            StageMut::Ready(mut ready_client) => {
+                ready_client.issue_command(command, &mut network);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn server_display_state(&self) -> GameDisplayState {
+        self.server.display_state().inner().clone()
+    }
+
+    /// The display state of a client, if it is ready.
+    pub fn client_display_state(&self, client_index: usize) -> Option<GameDisplayState> {
+        match self.clients[client_index].stage() {
+            Stage::Ready(ready_client) => {
+                let display_state: &GameDisplayState = &ready_client.display_state();
+                Some(display_state.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// The largest distance between a player's position on the server and on any client.
+    /// Returns `None` if a client is not ready or does not have the same players as the server.
+    pub fn max_position_error(&self) -> Option<Real> {
+        let server_state = self.server_display_state();
+        let mut max_error: Real = 0.0;
+        for client_index in 0..self.clients.len() {
+            let client_state = self.client_display_state(client_index)?;
+            if client_state.player_positions.len() != server_state.player_positions.len() {
+                return None;
+            }
+            for (player_id, server_position) in server_state.player_positions.iter() {
+                let client_position = client_state.player_positions.get(player_id)?;
+                let error = (client_position.translation.vector
+                    - server_position.translation.vector)
+                    .norm();
+                max_error = max_error.max(error);
+            }
+        }
+        Some(max_error)
+    }
+
+    /// Let the server know which snapshots a client has received, like the client does.
+    fn acknowledge_snapshots(&mut self, client_index: usize) {
+        let snapshots = self.received[client_index].take_acknowledgements();
+        if snapshots.is_empty() {
+            return;
+        }
+        GameNetwork::client(
+            &mut self.client_nets[client_index],
+            &mut self.received[client_index],
+            &mut self.client_stats[client_index],
+        )
+        .send_handshake(SERVER_HANDLE, HandshakeMessage::Acknowledge { snapshots });
+    }
+
+    /// Delta-encode the server's snapshots against the ones that the clients have acknowledged,
+    /// like the server does.
+    fn handle_acknowledgements(&mut self) {
+        let messages =
+            GameNetwork::server(&mut self.server_net, &mut self.sent, &mut self.server_stats)
+                .recv_handshakes();
+        for (handle, message) in messages {
+            if let HandshakeMessage::Acknowledge { snapshots } = message {
+                self.sent.acknowledge(handle, &snapshots);
+            }
+        }
+    }
+
+    /// Put the messages that were sent on the links.
+    fn transmit(&mut self) {
+        let arrives_at = self.clock + self.latency;
+        for (client_index, link) in self.links.iter_mut().enumerate() {
+            if !link.joined {
+                continue;
+            }
+            let handle = client_index as ConnectionHandle;
+            if let Some(channels) = self.server_net.connections.get_mut(&handle) {
+                let messages = channels.outgoing.drain(..).collect();
+                link.to_client
+                    .send(messages, arrives_at, link.loss, &mut self.random);
+            }
+            if let Some(channels) = self.client_nets[client_index]
+                .connections
+                .get_mut(&SERVER_HANDLE)
+            {
+                let messages = channels.outgoing.drain(..).collect();
+                link.to_server
+                    .send(messages, arrives_at, link.loss, &mut self.random);
+            }
+        }
+    }
+
+    /// Hand the messages that have arrived to the server and the clients.
+    fn deliver(&mut self) {
+        for (client_index, link) in self.links.iter_mut().enumerate() {
+            let handle = client_index as ConnectionHandle;
+            if let Some(channels) = self.server_net.connections.get_mut(&handle) {
+                link.to_server.deliver(self.clock, channels);
+            }
+            if let Some(channels) = self.client_nets[client_index]
+                .connections
+                .get_mut(&SERVER_HANDLE)
+            {
+                link.to_client.deliver(self.clock, channels);
+            }
+        }
+    }
+}
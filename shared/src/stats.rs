@@ -0,0 +1,209 @@
+//! Counters of what the netcode does, for the client's statistics overlay and the server's logs
+//! and metrics.
+//!
+//! The messages are counted in [`NetworkStats`] as [`GameNetwork`] sends and receives them.
+//! Steps and snapshots applied are counted in globals, because crystalorb steps and rolls back
+//! the worlds itself. The counts only ever go up; compare two readings to get a rate.
+//!
+//! [`GameNetwork`]: crate::network::connection::GameNetwork
+
+use crate::crystalorb_bevy_networking_turbulence::bevy_networking_turbulence::ConnectionHandle;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+/// Snapshots applied to a world. On a client, each one is a rollback.
+pub static SNAPSHOTS_APPLIED: Counter = Counter::new();
+/// Steps simulated by all worlds of this process, including resimulated and fast-forwarded steps.
+pub static STEPS: Counter = Counter::new();
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The network channels whose messages are counted. Crystalorb's clock sync messages are not
+/// counted; they are in the connections' totals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Command,
+    Snapshot,
+    Handshake,
+}
+
+/// The messages sent and received on a network channel, and their serialized size. The size
+/// includes crystalorb's timestamp, but not the channel's overhead or resent packets.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct ChannelCounts {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+/// What was sent and received on one connection, or on all of them.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub commands: ChannelCounts,
+    pub snapshots: ChannelCounts,
+    pub handshake: ChannelCounts,
+    /// Commands from the client that the server dropped, because the client may not issue them.
+    pub commands_rejected: u64,
+    /// Errors reported by the connection.
+    pub errors: u64,
+    /// Round trip time of the last answered ping, in seconds.
+    pub round_trip_time: Option<f64>,
+    /// How far the server's clock is ahead of the client's, in seconds, as measured by the last
+    /// clock sync message. Crystalorb averages several of these to sync the client's clock.
+    pub clock_offset: Option<f64>,
+}
+
+/// The statistics of each open connection, and the totals of all connections since startup.
+#[derive(Default, Debug)]
+pub struct NetworkStats {
+    connections: HashMap<ConnectionHandle, ConnectionStats>,
+    total: ConnectionStats,
+}
+
+impl ConnectionStats {
+    pub fn channel(&self, channel: Channel) -> &ChannelCounts {
+        match channel {
+            Channel::Command => &self.commands,
+            Channel::Snapshot => &self.snapshots,
+            Channel::Handshake => &self.handshake,
+        }
+    }
+
+    fn channel_mut(&mut self, channel: Channel) -> &mut ChannelCounts {
+        match channel {
+            Channel::Command => &mut self.commands,
+            Channel::Snapshot => &mut self.snapshots,
+            Channel::Handshake => &mut self.handshake,
+        }
+    }
+}
+
+impl NetworkStats {
+    /// The statistics of a connection, if anything has been counted on it since it opened.
+    pub fn connection(&self, handle: ConnectionHandle) -> Option<&ConnectionStats> {
+        self.connections.get(&handle)
+    }
+
+    /// The totals of all connections. The round trip time and clock offset are the last ones
+    /// measured.
+    pub fn total(&self) -> &ConnectionStats {
+        &self.total
+    }
+
+    pub fn record_sent(&mut self, handle: ConnectionHandle, channel: Channel, bytes: u64) {
+        self.record(handle, |stats| {
+            let counts = stats.channel_mut(channel);
+            counts.messages_sent += 1;
+            counts.bytes_sent += bytes;
+        });
+    }
+
+    pub fn record_received(&mut self, handle: ConnectionHandle, channel: Channel, bytes: u64) {
+        self.record(handle, |stats| {
+            let counts = stats.channel_mut(channel);
+            counts.messages_received += 1;
+            counts.bytes_received += bytes;
+        });
+    }
+
+    pub fn record_rejected_command(&mut self, handle: ConnectionHandle) {
+        self.record(handle, |stats| stats.commands_rejected += 1);
+    }
+
+    pub fn record_error(&mut self, handle: ConnectionHandle) {
+        self.record(handle, |stats| stats.errors += 1);
+    }
+
+    pub fn record_round_trip_time(&mut self, handle: ConnectionHandle, seconds: f64) {
+        self.record(handle, |stats| stats.round_trip_time = Some(seconds));
+    }
+
+    pub fn record_clock_offset(&mut self, handle: ConnectionHandle, seconds: f64) {
+        self.record(handle, |stats| stats.clock_offset = Some(seconds));
+    }
+
+    /// Forget a closed connection. Its counts stay in the totals.
+    pub fn forget_connection(&mut self, handle: ConnectionHandle) {
+        self.connections.remove(&handle);
+    }
+
+    fn record(&mut self, handle: ConnectionHandle, record: impl Fn(&mut ConnectionStats)) {
+        record(self.connections.entry(handle).or_default());
+        record(&mut self.total);
+    }
+}
+
+/// Counts of values in buckets with fixed upper bounds, like a Prometheus histogram.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Upper bounds of the buckets, ascending. Larger values are only in the total count.
+    bounds: Vec<f64>,
+    /// How many values fell in each bucket, not counting the smaller buckets.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: Vec<f64>) -> Self {
+        Self {
+            counts: vec![0; bounds.len()],
+            bounds,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Write the histogram in the Prometheus text format.
+    pub fn write_prometheus(&self, out: &mut String, name: &str, help: &str) {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} histogram\n",
+            name, help, name
+        ));
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            out.push_str(&format!(
+                "{}_bucket{{le=\"{}\"}} {}\n",
+                name, bound, cumulative
+            ));
+        }
+        out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, self.count));
+        out.push_str(&format!("{}_sum {}\n", name, self.sum));
+        out.push_str(&format!("{}_count {}\n", name, self.count));
+    }
+}
+
+/// Write a gauge or counter in the Prometheus text format.
+pub fn write_prometheus(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+        name, help, name, kind, name, value
+    ));
+}
@@ -0,0 +1,81 @@
+use orbgame_shared::{
+    arena::{Arena, Shape, StaticShape},
+    config::ConfigError,
+};
+use std::path::PathBuf;
+
+fn arena_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../arenas")
+        .join(name)
+}
+
+#[test]
+fn box_arena_file_matches_builtin_arena() {
+    let arena = Arena::load(&arena_path("box.toml")).unwrap();
+    assert_eq!(arena, Arena::default());
+}
+
+#[test]
+fn bundled_arenas_load() {
+    let arena = Arena::load(&arena_path("hills.toml")).unwrap();
+    assert_eq!(arena.spawn_points.len(), 4);
+    assert_eq!(arena.shapes.len(), 4);
+    assert_eq!(arena.zones.len(), 3);
+    assert_eq!(arena.pickups.len(), 3);
+
+    let arena = Arena::load(&arena_path("climb.toml")).unwrap();
+    assert_eq!(arena.zones.len(), 3);
+    assert_eq!(arena.pickups.len(), 2);
+}
+
+#[test]
+fn shapes_rapier_cannot_build_are_rejected() {
+    let with_shape = |shape: Shape| {
+        let mut arena = Arena::default();
+        arena.shapes.push(StaticShape {
+            shape,
+            position: [0.0, 0.0],
+            rotation: 0.0,
+            restitution: 0.5,
+            friction: 0.5,
+        });
+        arena
+    };
+    assert!(Arena::default().validate().is_ok());
+    let bad_shapes = vec![
+        Shape::Heightfield {
+            heights: vec![1.0],
+            scale: [10.0, 1.0],
+        },
+        Shape::Heightfield {
+            heights: vec![1.0, 2.0],
+            scale: [f32::NAN, 1.0],
+        },
+        Shape::Heightfield {
+            heights: vec![1.0, 2.0],
+            scale: [0.0, 1.0],
+        },
+        Shape::Polyline {
+            points: vec![[0.0, 0.0]],
+        },
+        Shape::Ball { radius: -1.0 },
+    ];
+    for shape in bad_shapes {
+        assert!(
+            matches!(
+                with_shape(shape.clone()).validate(),
+                Err(ConfigError::Invalid(_))
+            ),
+            "{:?} is accepted",
+            shape
+        );
+    }
+}
+
+#[test]
+fn arena_needs_spawn_points() {
+    let mut arena = Arena::default();
+    arena.spawn_points.clear();
+    assert!(matches!(arena.validate(), Err(ConfigError::Invalid(_))));
+}
@@ -0,0 +1,78 @@
+use orbgame_shared::{
+    bevy::prelude::*,
+    bindings::{Binding, Bindings, InputDevices},
+    config::Settings,
+    game::input::FULL_STEERING,
+};
+use std::path::PathBuf;
+
+#[derive(Default)]
+struct Devices {
+    keys: Input<KeyCode>,
+    mouse_buttons: Input<MouseButton>,
+    gamepad_buttons: Input<GamepadButton>,
+    gamepad_axes: Axis<GamepadAxis>,
+}
+
+impl Devices {
+    fn input(&self, bindings: &Bindings) -> orbgame_shared::game::PlayerInput {
+        bindings.input(&InputDevices {
+            keys: &self.keys,
+            mouse_buttons: &self.mouse_buttons,
+            gamepad_buttons: &self.gamepad_buttons,
+            gamepad_axes: &self.gamepad_axes,
+        })
+    }
+}
+
+#[test]
+fn example_config_has_bindings() {
+    let settings =
+        Settings::load(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../orbgame.example.toml"))
+            .unwrap();
+    assert_eq!(settings.bindings.len(), 1);
+    assert!(settings.bindings[0]
+        .dash
+        .contains(&Binding::Mouse(MouseButton::Left)));
+}
+
+#[test]
+fn two_players_can_share_a_keyboard() {
+    let arrows = Bindings::default();
+    let wasd = Bindings {
+        gamepad: 1,
+        left: vec![Binding::Key(KeyCode::A)],
+        right: vec![Binding::Key(KeyCode::D)],
+        jump: vec![Binding::Key(KeyCode::W)],
+        ..Bindings::default()
+    };
+    let mut devices = Devices::default();
+    devices.keys.press(KeyCode::Left);
+    devices.keys.press(KeyCode::W);
+
+    let arrows_input = devices.input(&arrows);
+    assert_eq!(arrows_input.steering, -FULL_STEERING);
+    assert!(!arrows_input.jump);
+    let wasd_input = devices.input(&wasd);
+    assert_eq!(wasd_input.steering, 0);
+    assert!(wasd_input.jump);
+}
+
+#[test]
+fn gamepad_axes_steer_and_press() {
+    let bindings = Bindings {
+        ground_pound: vec![Binding::Axis(GamepadAxisType::LeftStickY, -0.8)],
+        ..Bindings::default()
+    };
+    let mut devices = Devices::default();
+    devices
+        .gamepad_axes
+        .set(GamepadAxis(Gamepad(0), GamepadAxisType::LeftStickX), 0.5);
+    devices
+        .gamepad_axes
+        .set(GamepadAxis(Gamepad(0), GamepadAxisType::LeftStickY), -0.9);
+
+    let input = devices.input(&bindings);
+    assert_eq!(input.steering, 64);
+    assert!(input.ground_pound);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::moving_world;
+use crystalorb::{fixed_timestepper::Stepper, world::World};
+use orbgame_shared::game::{
+    codec::{self, CodecError, SnapshotPrecision},
+    GameSnapshot, GameWorld,
+};
+
+fn assert_positions_close(world: &GameWorld, decoded: &GameWorld, tolerance: f32) {
+    let positions = world.display_state().player_positions;
+    let decoded_positions = decoded.display_state().player_positions;
+    assert_eq!(positions.len(), decoded_positions.len());
+    for (player_id, position) in positions.iter() {
+        let distance =
+            (position.translation.vector - decoded_positions[player_id].translation.vector).norm();
+        assert!(
+            distance <= tolerance,
+            "player {} is {} away",
+            player_id,
+            distance
+        );
+    }
+}
+
+/// The world after a few more steps, and a snapshot of it from before them.
+fn moved_world() -> (GameWorld, GameSnapshot) {
+    let mut world = moving_world();
+    let baseline = world.snapshot();
+    for _ in 0..6 {
+        world.step();
+    }
+    (world, baseline)
+}
+
+fn decoded(snapshot: GameSnapshot) -> GameWorld {
+    let mut world = GameWorld::new();
+    world.apply_snapshot(snapshot);
+    world
+}
+
+#[test]
+fn quantized_snapshots_are_within_the_precision() {
+    let (world, _) = moved_world();
+    let precision = SnapshotPrecision::default();
+    let encoded = codec::encode(&world.snapshot(), None, precision);
+    let decoded = decoded(codec::decode(&encoded, None).unwrap());
+    // Within half a step of the precision
+    assert_positions_close(&world, &decoded, 1.0 / 64.0);
+}
+
+#[test]
+fn delta_encoded_snapshots_are_smaller_and_the_same() {
+    let (world, baseline) = moved_world();
+    let snapshot = world.snapshot();
+    let precision = SnapshotPrecision::default();
+    let full = codec::encode(&snapshot, None, precision);
+    let delta = codec::encode(&snapshot, Some(&baseline), precision);
+    assert!(delta.len() < full.len());
+    let decoded_full = decoded(codec::decode(&full, None).unwrap());
+    let decoded_delta = decoded(codec::decode(&delta, Some(&baseline)).unwrap());
+    assert_positions_close(&decoded_full, &decoded_delta, 0.0);
+    assert!(matches!(
+        codec::decode(&delta, None),
+        Err(CodecError::MissingBaseline)
+    ));
+}
+
+#[test]
+fn unquantized_snapshots_are_exact() {
+    let (world, _) = moved_world();
+    let precision = SnapshotPrecision {
+        quantize: false,
+        ..Default::default()
+    };
+    let encoded = codec::encode(&world.snapshot(), None, precision);
+    let decoded = decoded(codec::decode(&encoded, None).unwrap());
+    assert_positions_close(&world, &decoded, 0.0);
+}
+
+#[test]
+fn truncated_snapshots_are_not_decoded() {
+    assert!(matches!(
+        codec::decode(&[], None),
+        Err(CodecError::Truncated)
+    ));
+}
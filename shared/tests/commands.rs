@@ -0,0 +1,231 @@
+use crystalorb::world::World;
+use orbgame_shared::{
+    game::{
+        input::FULL_STEERING, ClientHandle, ClientSlot, GameCommand, GameWorld, PlayerId,
+        PlayerInput, MAX_LOCAL_PLAYERS,
+    },
+    match_state::MatchSettings,
+};
+
+/// The player ids a client has been given.
+fn player_ids(world: &GameWorld, client_handle: ClientHandle) -> Vec<PlayerId> {
+    world.display_state().clients[&client_handle]
+        .iter()
+        .filter_map(|slot| match slot {
+            ClientSlot::Player(player_id) => Some(*player_id),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn clients_get_distinct_player_ids() {
+    let mut world = GameWorld::new();
+    for client_handle in &[40, 2, 17] {
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle: *client_handle,
+            local_players: 1,
+        });
+    }
+    // Ids are allocated in order of spawning, not by client handle
+    assert_eq!(player_ids(&world, 40), vec![PlayerId(0)]);
+    assert_eq!(player_ids(&world, 2), vec![PlayerId(1)]);
+    assert_eq!(player_ids(&world, 17), vec![PlayerId(2)]);
+    assert_eq!(world.display_state().player_positions.len(), 3);
+}
+
+#[test]
+fn player_ids_are_reused_after_despawn() {
+    let mut world = GameWorld::new();
+    for client_handle in 0..3 {
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle,
+            local_players: 1,
+        });
+    }
+    world.apply_command(&GameCommand::DespawnPlayer { client_handle: 1 });
+    assert!(!world.display_state().clients.contains_key(&1));
+
+    // The lowest free id is reused, the other players keep theirs
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 7,
+        local_players: 1,
+    });
+    assert_eq!(player_ids(&world, 7), vec![PlayerId(1)]);
+    assert_eq!(player_ids(&world, 0), vec![PlayerId(0)]);
+    assert_eq!(player_ids(&world, 2), vec![PlayerId(2)]);
+
+    // A despawned client can join again, and gets the next free id
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 1,
+        local_players: 1,
+    });
+    assert_eq!(player_ids(&world, 1), vec![PlayerId(3)]);
+}
+
+#[test]
+fn input_for_unknown_client_is_dropped() {
+    let mut world = GameWorld::new();
+    let input = PlayerInput {
+        jump: true,
+        ..Default::default()
+    };
+    world.apply_command(&GameCommand::SetInput(7, 0, input));
+    assert_eq!(world.display_state().dropped_commands, 1);
+}
+
+#[test]
+fn input_after_despawn_is_dropped() {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 3,
+        local_players: 1,
+    });
+    world.apply_command(&GameCommand::DespawnPlayer { client_handle: 3 });
+    let input = PlayerInput {
+        steering: -FULL_STEERING,
+        ..Default::default()
+    };
+    world.apply_command(&GameCommand::SetInput(3, 0, input));
+    let display_state = world.display_state();
+    assert!(display_state.player_positions.is_empty());
+    assert_eq!(display_state.dropped_commands, 1);
+}
+
+#[test]
+fn client_controls_several_players() {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 5,
+        local_players: 2,
+    });
+    let display_state = world.display_state();
+    assert_eq!(display_state.player_positions.len(), 2);
+    assert!(matches!(
+        display_state.clients[&5][..],
+        [ClientSlot::Player(_), ClientSlot::Player(_)]
+    ));
+
+    world.apply_command(&GameCommand::SetInput(5, 1, PlayerInput::default()));
+    assert_eq!(world.display_state().dropped_commands, 0);
+    world.apply_command(&GameCommand::SetInput(5, 2, PlayerInput::default()));
+    assert_eq!(world.display_state().dropped_commands, 1);
+
+    world.apply_command(&GameCommand::DespawnPlayer { client_handle: 5 });
+    assert!(world.display_state().player_positions.is_empty());
+}
+
+#[test]
+fn local_players_are_limited() {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 5,
+        local_players: 100,
+    });
+    assert_eq!(
+        world.display_state().clients[&5].len(),
+        MAX_LOCAL_PLAYERS as usize
+    );
+}
+
+#[test]
+fn spectator_has_no_players() {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::Spectate { client_handle: 9 });
+    let display_state = world.display_state();
+    assert!(display_state.player_positions.is_empty());
+    assert!(display_state.clients[&9].is_empty());
+
+    world.apply_command(&GameCommand::SetInput(9, 0, PlayerInput::default()));
+    assert_eq!(world.display_state().dropped_commands, 1);
+}
+
+#[test]
+fn clients_can_not_make_themselves_spectate() {
+    assert!(!GameWorld::command_is_valid(
+        &GameCommand::Spectate { client_handle: 11 },
+        11
+    ));
+}
+
+#[test]
+fn spectator_joins_and_leaves() {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::Spectate { client_handle: 9 });
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 9,
+        local_players: 1,
+    });
+    assert_eq!(world.display_state().player_positions.len(), 1);
+    assert_eq!(world.display_state().dropped_commands, 0);
+
+    world.apply_command(&GameCommand::Spectate { client_handle: 9 });
+    let display_state = world.display_state();
+    assert!(display_state.player_positions.is_empty());
+    assert!(display_state.clients[&9].is_empty());
+}
+
+#[test]
+fn players_are_limited() {
+    let mut world = GameWorld::new();
+    let max_players = MatchSettings::default().max_players as usize;
+    for client_handle in 0..max_players as ClientHandle {
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle,
+            local_players: 1,
+        });
+    }
+    assert_eq!(world.free_player_slots(), 0);
+    assert_eq!(world.display_state().free_player_slots, 0);
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 100,
+        local_players: 1,
+    });
+    let display_state = world.display_state();
+    assert_eq!(display_state.player_positions.len(), max_players);
+    assert!(matches!(
+        display_state.clients[&100][..],
+        [ClientSlot::Rejected(_)]
+    ));
+    assert!(display_state.is_spectating(100));
+}
+
+#[test]
+fn clients_join_with_all_their_players_or_none() {
+    let mut world = GameWorld::new();
+    let max_players = MatchSettings::default().max_players as usize;
+    for client_handle in 0..(max_players - 1) as ClientHandle {
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle,
+            local_players: 1,
+        });
+    }
+    // One slot is free, but the client has two players
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 100,
+        local_players: 2,
+    });
+    let display_state = world.display_state();
+    assert_eq!(display_state.player_positions.len(), max_players - 1);
+    assert!(matches!(
+        display_state.clients[&100][..],
+        [ClientSlot::Rejected(_), ClientSlot::Rejected(_)]
+    ));
+    assert!(display_state.is_spectating(100));
+    world.apply_command(&GameCommand::SetInput(100, 0, PlayerInput::default()));
+    assert_eq!(world.display_state().dropped_commands, 1);
+
+    // When another player leaves, the client can join
+    world.apply_command(&GameCommand::DespawnPlayer { client_handle: 0 });
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 100,
+        local_players: 2,
+    });
+    let display_state = world.display_state();
+    assert_eq!(display_state.player_positions.len(), max_players);
+    assert!(matches!(
+        display_state.clients[&100][..],
+        [ClientSlot::Player(_), ClientSlot::Player(_)]
+    ));
+    assert!(!display_state.is_spectating(100));
+}
@@ -0,0 +1,43 @@
+//! Fixtures shared by the test binaries. Each binary uses only some of them.
+#![allow(dead_code)]
+
+use crystalorb::{fixed_timestepper::Stepper, world::World};
+use orbgame_shared::game::{input::FULL_STEERING, GameCommand, GameWorld, PlayerInput};
+
+/// Set the input of a client's first player.
+pub fn steer(client_handle: u32, steering: i8, jump: bool) -> GameCommand {
+    GameCommand::SetInput(
+        client_handle,
+        0,
+        PlayerInput {
+            steering,
+            jump,
+            ..Default::default()
+        },
+    )
+}
+
+/// A world with two players that have been moving for a while.
+pub fn moving_world() -> GameWorld {
+    let mut world = GameWorld::new();
+    for client_handle in 0..2 {
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle,
+            local_players: 1,
+        });
+        world.apply_command(&steer(client_handle, FULL_STEERING, true));
+    }
+    step(&mut world, 30);
+    world
+}
+
+pub fn step(world: &mut GameWorld, ticks: usize) {
+    for _ in 0..ticks {
+        world.step();
+    }
+}
+
+/// Step the world for about this many seconds of the default timestep.
+pub fn step_seconds(world: &mut GameWorld, seconds: f64) {
+    step(world, (seconds / orbgame_shared::TIMESTEP).round() as usize);
+}
@@ -0,0 +1,44 @@
+use orbgame_shared::config::{ConfigError, Options, Settings};
+
+fn options(timestep: Option<f64>) -> Options {
+    Options {
+        config: None,
+        address: None,
+        port: None,
+        timestep,
+        arenas: Vec::new(),
+        spectate: false,
+    }
+}
+
+#[test]
+fn default_settings_are_valid() {
+    assert!(Settings::from_options(options(None)).is_ok());
+    assert!(Settings::from_options(options(Some(0.01))).is_ok());
+}
+
+#[test]
+fn timestep_must_be_positive() {
+    for timestep in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+        assert!(matches!(
+            Settings::from_options(options(Some(*timestep))),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+}
+
+#[test]
+fn log_period_must_be_positive() {
+    let mut settings = Settings::default();
+    settings.stats.log_period = 0.0;
+    assert!(matches!(settings.validate(), Err(ConfigError::Invalid(_))));
+    settings.stats.log_period = f64::NAN;
+    assert!(matches!(settings.validate(), Err(ConfigError::Invalid(_))));
+}
+
+#[test]
+fn bindings_must_not_be_empty() {
+    let mut settings = Settings::default();
+    settings.bindings.clear();
+    assert!(matches!(settings.validate(), Err(ConfigError::Invalid(_))));
+}
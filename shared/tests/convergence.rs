@@ -0,0 +1,123 @@
+use orbgame_shared::{
+    crystal_orb_config,
+    game::{GameCommand, PlayerInput},
+    simulation::Simulation,
+    TIMESTEP,
+};
+
+/// How far apart a player's position on a client and on the server may be after settling.
+const TOLERANCE: f32 = 1.0;
+
+fn ready_simulation() -> Simulation {
+    let mut simulation = Simulation::new(crystal_orb_config());
+    assert!(
+        simulation.run_until_ready(10.0, TIMESTEP),
+        "clients did not become ready"
+    );
+    simulation.spawn_players();
+    simulation
+}
+
+fn jump() -> PlayerInput {
+    PlayerInput {
+        jump: true,
+        ..Default::default()
+    }
+}
+
+fn assert_converged(simulation: &Simulation) {
+    let error = simulation
+        .max_position_error()
+        .expect("clients do not have the same players as the server");
+    assert!(
+        error < TOLERANCE,
+        "client and server positions differ by {}",
+        error
+    );
+}
+
+#[test]
+fn clients_see_spawned_players() {
+    let mut simulation = ready_simulation();
+    simulation.run_for(2.0, TIMESTEP);
+    assert_eq!(simulation.server_display_state().player_positions.len(), 2);
+    for client_index in 0..2 {
+        let display_state = simulation.client_display_state(client_index).unwrap();
+        assert_eq!(display_state.player_positions.len(), 2);
+    }
+}
+
+#[test]
+fn clients_converge_without_latency() {
+    let mut simulation = ready_simulation();
+    simulation.run_for(1.0, TIMESTEP);
+    simulation.issue_input(0, jump());
+    simulation.run_for(5.0, TIMESTEP);
+    assert_converged(&simulation);
+}
+
+#[test]
+fn clients_converge_with_latency() {
+    let mut simulation = ready_simulation();
+    simulation.set_latency(0.1);
+    simulation.run_for(1.0, TIMESTEP);
+    simulation.issue_input(0, jump());
+    simulation.issue_input(1, jump());
+    simulation.run_for(5.0, TIMESTEP);
+    assert_converged(&simulation);
+}
+
+#[test]
+fn clients_converge_after_packet_loss() {
+    let mut simulation = ready_simulation();
+    simulation.set_latency(0.05);
+    simulation.set_packet_loss(1, 0.2);
+    simulation.run_for(1.0, TIMESTEP);
+    simulation.issue_input(0, jump());
+    simulation.run_for(3.0, TIMESTEP);
+    simulation.set_packet_loss(1, 0.0);
+    simulation.run_for(5.0, TIMESTEP);
+    assert_converged(&simulation);
+}
+
+#[test]
+fn clients_joining_a_running_game_converge() {
+    let mut simulation = Simulation::with_clients(crystal_orb_config(), 4);
+    simulation.set_latency(0.05);
+    for client_index in 0..4 {
+        simulation.join(client_index);
+        simulation.run_for(1.5, TIMESTEP);
+        // Clients that have become ready get a player and start moving
+        simulation.spawn_players();
+        simulation.issue_input(0, jump());
+    }
+    assert!(
+        simulation.run_until_ready(10.0, TIMESTEP),
+        "clients did not become ready"
+    );
+    simulation.spawn_players();
+    for client_index in 0..4 {
+        simulation.issue_input(client_index, jump());
+    }
+    simulation.run_for(5.0, TIMESTEP);
+    assert_eq!(simulation.server_display_state().player_positions.len(), 4);
+    assert_converged(&simulation);
+}
+
+#[test]
+fn server_rejects_input_for_another_client() {
+    let mut simulation = ready_simulation();
+    simulation.run_for(1.0, TIMESTEP);
+    // The server's handle of the second client is its index
+    assert!(simulation.issue_command(0, GameCommand::SetInput(1, 0, jump())));
+    simulation.run_for(1.0, TIMESTEP);
+    assert_eq!(simulation.server_stats().total().commands_rejected, 1);
+    assert_eq!(
+        simulation
+            .server_stats()
+            .connection(0)
+            .unwrap()
+            .commands_rejected,
+        1
+    );
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use common::steer;
+use crystalorb::{fixed_timestepper::Stepper, world::World};
+use orbgame_shared::game::{input::FULL_STEERING, GameCommand, GameWorld};
+
+#[test]
+fn worlds_with_the_same_history_have_identical_snapshots() {
+    // Each world would iterate a hash map of its players in a different order
+    let mut worlds = vec![GameWorld::new(), GameWorld::new()];
+    for world in worlds.iter_mut() {
+        for client_handle in 0..8 {
+            world.apply_command(&GameCommand::SpawnPlayer {
+                client_handle,
+                local_players: 1,
+            });
+        }
+        world.apply_command(&GameCommand::DespawnPlayer { client_handle: 3 });
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle: 8,
+            local_players: 1,
+        });
+        for client_handle in 0..9 {
+            let steering = if client_handle % 2 == 0 {
+                FULL_STEERING
+            } else {
+                -FULL_STEERING
+            };
+            world.apply_command(&steer(client_handle, steering, true));
+        }
+        for _ in 0..60 {
+            world.step();
+        }
+    }
+    assert_eq!(
+        format!("{:?}", worlds[0].snapshot()),
+        format!("{:?}", worlds[1].snapshot())
+    );
+    let player_ids: Vec<_> = worlds[0]
+        .display_state()
+        .player_positions
+        .keys()
+        .copied()
+        .collect();
+    let mut sorted = player_ids.clone();
+    sorted.sort();
+    assert_eq!(player_ids, sorted);
+}
@@ -0,0 +1,54 @@
+use crystalorb::{fixed_timestepper::Stepper, world::World};
+use orbgame_shared::{
+    game::{events::CollisionEvent, GameCommand, GameWorld},
+    PlayerId,
+};
+
+#[test]
+fn collisions_show_up_in_display_state() {
+    // The built-in arena has one spawn point, so both players start on top of each other
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 1,
+        local_players: 1,
+    });
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 2,
+        local_players: 1,
+    });
+
+    let mut events = Vec::new();
+    let mut last_step = 0;
+    for _ in 0..300 {
+        world.step();
+        let display_state = world.display_state();
+        events.extend(
+            display_state
+                .events
+                .iter()
+                .filter(|(step_number, _)| *step_number > last_step)
+                .map(|(_, event)| *event),
+        );
+        last_step = display_state.step_number;
+    }
+    assert!(events.contains(&CollisionEvent::PlayersTouched(PlayerId(0), PlayerId(1))));
+    assert!(events.contains(&CollisionEvent::HitArena(PlayerId(0))));
+}
+
+#[test]
+fn display_state_only_keeps_recent_events() {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 1,
+        local_players: 1,
+    });
+    for _ in 0..300 {
+        world.step();
+    }
+    let display_state = world.display_state();
+    assert_eq!(display_state.step_number, 300);
+    assert!(display_state
+        .events
+        .iter()
+        .all(|(step_number, _)| display_state.step_number - step_number < 30));
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::steer;
+use crystalorb::{fixed_timestepper::Stepper, world::World};
+use orbgame_shared::{
+    arena::Arena,
+    game::{input::FULL_STEERING, GameCommand, GameWorld, WorldSettings},
+    match_state::MatchSettings,
+};
+
+/// The commands issued in each step of the replay.
+fn commands(step: usize) -> Vec<GameCommand> {
+    match step {
+        0 => vec![
+            steer(1, FULL_STEERING, false),
+            steer(2, -FULL_STEERING, true),
+        ],
+        30 => vec![steer(1, FULL_STEERING, true)],
+        31 => vec![steer(1, -FULL_STEERING / 2, false)],
+        90 => vec![steer(2, FULL_STEERING, true)],
+        _ => Vec::new(),
+    }
+}
+
+fn spawn_players(world: &mut GameWorld) {
+    for client_handle in 1..=2 {
+        world.apply_command(&GameCommand::SpawnPlayer {
+            client_handle,
+            local_players: 1,
+        });
+    }
+}
+
+#[test]
+fn replay_from_full_snapshot_is_bit_identical() {
+    let mut server = GameWorld::new();
+    server.apply_command(&GameCommand::ConfigureMatch {
+        settings: MatchSettings::default(),
+        world: WorldSettings {
+            full_physics_snapshots: true,
+            ..Default::default()
+        },
+        rotation: vec![Arena::default()],
+        rules: Default::default(),
+    });
+    spawn_players(&mut server);
+    for step in 0..60 {
+        for command in commands(step) {
+            server.apply_command(&command);
+        }
+        server.step();
+    }
+
+    // A client with a different history, whose contacts and islands differ from the server's
+    let mut client = GameWorld::new();
+    spawn_players(&mut client);
+    client.apply_command(&steer(2, FULL_STEERING, true));
+    for _ in 0..17 {
+        client.step();
+    }
+
+    // The client gets full physics snapshots with the world
+    client.apply_snapshot(server.snapshot());
+    for step in 60..180 {
+        for command in commands(step) {
+            server.apply_command(&command);
+            client.apply_command(&command);
+        }
+        server.step();
+        client.step();
+    }
+
+    // The serialized snapshots include the whole physics state, bit for bit
+    assert_eq!(
+        bincode::serialize(&server.snapshot()).unwrap(),
+        bincode::serialize(&client.snapshot()).unwrap()
+    );
+    let server_positions = server.display_state().player_positions;
+    let client_positions = client.display_state().player_positions;
+    assert_eq!(server_positions.len(), 2);
+    for (player_id, position) in server_positions.iter() {
+        let client_position = client_positions[player_id];
+        assert_eq!(
+            position.translation.vector.x.to_bits(),
+            client_position.translation.vector.x.to_bits()
+        );
+        assert_eq!(
+            position.translation.vector.y.to_bits(),
+            client_position.translation.vector.y.to_bits()
+        );
+    }
+}
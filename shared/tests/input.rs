@@ -0,0 +1,26 @@
+use orbgame_shared::game::{
+    input::{quantize_steering, FULL_STEERING},
+    PlayerAction, PlayerInput,
+};
+
+#[test]
+fn steering_is_quantized_and_clamped() {
+    assert_eq!(quantize_steering(0.0), 0);
+    assert_eq!(quantize_steering(1.0), FULL_STEERING);
+    assert_eq!(quantize_steering(-3.0), -FULL_STEERING);
+    assert_eq!(quantize_steering(0.5), 64);
+}
+
+#[test]
+fn input_round_trips_through_two_bytes() {
+    let mut input = PlayerInput {
+        steering: -42,
+        ..Default::default()
+    };
+    input.set_action(PlayerAction::Dash, true);
+    input.set_action(PlayerAction::GroundPound, true);
+    let bytes: [u8; 2] = input.into();
+    assert_eq!(PlayerInput::from(bytes), input);
+    assert!(input.action(PlayerAction::Dash));
+    assert!(!input.action(PlayerAction::Jump));
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::step;
+use crystalorb::world::World;
+use orbgame_shared::{
+    arena::Arena,
+    game::{GameCommand, GameWorld, WorldSettings},
+    match_state::{MatchPhase, MatchSettings},
+};
+use std::path::PathBuf;
+
+/// Settings where every phase takes a few ticks.
+fn quick_settings() -> MatchSettings {
+    let tick = orbgame_shared::TIMESTEP;
+    MatchSettings {
+        warmup_seconds: 2.0 * tick,
+        round_seconds: 3.0 * tick,
+        round_end_seconds: 2.0 * tick,
+        intermission_seconds: 2.0 * tick,
+        rounds_per_arena: 2,
+        ..MatchSettings::default()
+    }
+}
+
+#[test]
+fn match_goes_through_rounds_and_rotates_arenas() {
+    let hills =
+        Arena::load(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../arenas/hills.toml"))
+            .unwrap();
+    let rotation = vec![Arena::default(), hills];
+
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::ConfigureMatch {
+        settings: quick_settings(),
+        world: Default::default(),
+        rotation: rotation.clone(),
+        rules: Default::default(),
+    });
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 1,
+        local_players: 1,
+    });
+    assert_eq!(world.display_state().phase, MatchPhase::Warmup);
+
+    let mut phases = vec![(MatchPhase::Warmup, 0)];
+    for _ in 0..40 {
+        step(&mut world, 1);
+        let display_state = world.display_state();
+        let phase = (display_state.phase, display_state.round);
+        if phases.last() != Some(&phase) {
+            phases.push(phase);
+        }
+        if phase == (MatchPhase::Warmup, 0) {
+            assert_eq!(display_state.arena_id, rotation[1].id());
+            break;
+        }
+        assert_eq!(display_state.arena_id, rotation[0].id());
+    }
+    assert_eq!(
+        phases,
+        vec![
+            (MatchPhase::Warmup, 0),
+            (MatchPhase::Playing, 1),
+            (MatchPhase::RoundEnd, 1),
+            (MatchPhase::Playing, 2),
+            (MatchPhase::RoundEnd, 2),
+            (MatchPhase::Intermission, 2),
+            (MatchPhase::Warmup, 0),
+        ]
+    );
+}
+
+#[test]
+fn snapshot_carries_match_state() {
+    let mut server_world = GameWorld::new();
+    server_world.apply_command(&GameCommand::ConfigureMatch {
+        settings: quick_settings(),
+        world: Default::default(),
+        rotation: vec![Arena::default()],
+        rules: Default::default(),
+    });
+    step(&mut server_world, 3);
+
+    let mut client_world = GameWorld::new();
+    client_world.apply_snapshot(server_world.snapshot());
+    let server_state = server_world.display_state();
+    let client_state = client_world.display_state();
+    assert_eq!(client_state.phase, MatchPhase::Playing);
+    assert_eq!(client_state.phase, server_state.phase);
+    assert_eq!(client_state.round, server_state.round);
+    assert_eq!(client_state.seconds_left, server_state.seconds_left);
+}
+
+#[test]
+fn snapshot_carries_arenas_and_world_settings() {
+    let hills =
+        Arena::load(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../arenas/hills.toml"))
+            .unwrap();
+    let world_settings = WorldSettings {
+        timestep: 1.0 / 30.0,
+        full_physics_snapshots: true,
+    };
+    let mut server_world = GameWorld::new();
+    server_world.apply_command(&GameCommand::ConfigureMatch {
+        settings: quick_settings(),
+        world: world_settings,
+        rotation: vec![hills.clone()],
+        rules: Default::default(),
+    });
+    step(&mut server_world, 1);
+
+    // A new world only knows the arena that worlds start in
+    let mut client_world = GameWorld::new();
+    client_world.apply_snapshot(server_world.snapshot());
+    let client_state = client_world.display_state();
+    assert_eq!(client_state.arena_id, hills.id());
+    assert_eq!(*client_state.arena, hills);
+    assert_eq!(
+        client_state.seconds_left,
+        server_world.display_state().seconds_left
+    );
+
+    // The client steps with the server's timestep
+    step(&mut server_world, 1);
+    step(&mut client_world, 1);
+    assert_eq!(
+        client_world.display_state().seconds_left,
+        server_world.display_state().seconds_left
+    );
+}
@@ -0,0 +1,79 @@
+use orbgame_shared::{
+    game::{
+        events::CollisionEvent,
+        modes::{GameMode, GameModeKind, StepContext},
+    },
+    PlayerId,
+};
+use std::collections::BTreeMap;
+
+const A: PlayerId = PlayerId(0);
+const B: PlayerId = PlayerId(1);
+const C: PlayerId = PlayerId(2);
+
+/// Step a mode for `ticks` ticks, with the same things happening in each tick.
+fn run(
+    mode: &mut GameMode,
+    ticks: usize,
+    in_hill: &[PlayerId],
+    events: &[CollisionEvent],
+) -> BTreeMap<PlayerId, i32> {
+    let mut scores = BTreeMap::new();
+    for _ in 0..ticks {
+        mode.rules_mut().step(&mut StepContext {
+            players: &[A, B, C],
+            in_hill,
+            events,
+            scores: &mut scores,
+            timestep: orbgame_shared::TIMESTEP,
+        });
+    }
+    scores
+}
+
+fn ticks_per_second() -> usize {
+    (1.0 / orbgame_shared::TIMESTEP).round() as usize
+}
+
+#[test]
+fn king_of_the_hill_scores_only_when_alone() {
+    let mut mode = GameMode::new(GameModeKind::KingOfTheHill);
+    mode.rules_mut().round_started(1, &[A, B, C]);
+    let scores = run(&mut mode, 2 * ticks_per_second(), &[A], &[]);
+    assert_eq!(scores.get(&A), Some(&2));
+
+    let scores = run(&mut mode, 2 * ticks_per_second(), &[A, B], &[]);
+    assert!(scores.is_empty());
+}
+
+#[test]
+fn knockout_credits_the_last_toucher() {
+    let mut mode = GameMode::new(GameModeKind::Knockout);
+    mode.rules_mut().round_started(1, &[A, B, C]);
+    run(&mut mode, 1, &[], &[CollisionEvent::PlayersTouched(A, B)]);
+    let scores = run(&mut mode, 1, &[], &[CollisionEvent::KnockedOut(B)]);
+    assert_eq!(scores.get(&A), Some(&1));
+
+    // Without a recent touch, falling out costs a point
+    let scores = run(&mut mode, 1, &[], &[CollisionEvent::KnockedOut(C)]);
+    assert_eq!(scores.get(&C), Some(&-1));
+}
+
+#[test]
+fn tag_passes_it_on_touch() {
+    let mut mode = GameMode::new(GameModeKind::Tag);
+    mode.rules_mut().round_started(0, &[A, B, C]);
+    assert_eq!(mode.rules().marked_player(), Some(A));
+
+    run(&mut mode, 1, &[], &[CollisionEvent::PlayersTouched(A, B)]);
+    assert_eq!(mode.rules().marked_player(), Some(B));
+
+    // No tag backs right away
+    run(&mut mode, 1, &[], &[CollisionEvent::PlayersTouched(A, B)]);
+    assert_eq!(mode.rules().marked_player(), Some(B));
+
+    // Players that are not it score over time
+    let scores = run(&mut mode, ticks_per_second(), &[], &[]);
+    assert_eq!(scores.get(&A), Some(&1));
+    assert_eq!(scores.get(&B), None);
+}
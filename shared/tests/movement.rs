@@ -0,0 +1,74 @@
+mod common;
+
+use common::step_seconds;
+use crystalorb::{fixed_timestepper::Stepper, world::World};
+use orbgame_shared::{
+    game::{GameCommand, GameWorld, PlayerInput},
+    PlayerId,
+};
+
+fn height(world: &GameWorld) -> f32 {
+    world.display_state().player_positions[&PlayerId(0)]
+        .translation
+        .vector
+        .y
+}
+
+/// Press jump for one step.
+fn jump(world: &mut GameWorld) {
+    let input = PlayerInput {
+        jump: true,
+        ..Default::default()
+    };
+    world.apply_command(&GameCommand::SetInput(1, 0, input));
+    world.step();
+    world.apply_command(&GameCommand::SetInput(1, 0, PlayerInput::default()));
+}
+
+/// A world with one player that has landed on the floor.
+fn world_with_landed_player() -> GameWorld {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 1,
+        local_players: 1,
+    });
+    step_seconds(&mut world, 3.0);
+    world
+}
+
+#[test]
+fn jumping_from_the_ground_lifts_the_player() {
+    let mut world = world_with_landed_player();
+    let ground = height(&world);
+    jump(&mut world);
+    step_seconds(&mut world, 0.2);
+    assert!(height(&world) > ground + 5.0);
+}
+
+#[test]
+fn jumping_in_the_air_does_nothing() {
+    let mut jumping = world_with_landed_player();
+    jump(&mut jumping);
+    step_seconds(&mut jumping, 0.3);
+    let mut falling = GameWorld::new();
+    falling.apply_snapshot(jumping.snapshot());
+
+    jump(&mut jumping);
+    falling.step();
+    step_seconds(&mut jumping, 0.2);
+    step_seconds(&mut falling, 0.2);
+    assert!((height(&jumping) - height(&falling)).abs() < 0.01);
+}
+
+#[test]
+fn holding_jump_only_jumps_once() {
+    let mut world = world_with_landed_player();
+    let ground = height(&world);
+    let input = PlayerInput {
+        jump: true,
+        ..Default::default()
+    };
+    world.apply_command(&GameCommand::SetInput(1, 0, input));
+    step_seconds(&mut world, 3.0);
+    assert!((height(&world) - ground).abs() < 1.0);
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::step_seconds;
+use crystalorb::world::World;
+use orbgame_shared::{
+    arena::{Arena, PickupKind, PickupSpawn},
+    game::{events::CollisionEvent, GameCommand, GameWorld},
+    match_state::MatchSettings,
+    PlayerId,
+};
+
+/// A world in an arena with a pickup at the spawn point, with one player.
+fn world_with_pickup() -> GameWorld {
+    let mut arena = Arena::default();
+    arena.name = "Pickup test".to_string();
+    arena.pickups.push(PickupSpawn {
+        kind: PickupKind::SpeedBoost,
+        position: arena.spawn_points[0],
+        respawn_seconds: 1.0,
+    });
+
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::ConfigureMatch {
+        settings: MatchSettings::default(),
+        world: Default::default(),
+        rotation: vec![arena],
+        rules: Default::default(),
+    });
+    world.apply_command(&GameCommand::SpawnPlayer {
+        client_handle: 1,
+        local_players: 1,
+    });
+    world
+}
+
+#[test]
+fn pickups_are_picked_up_and_come_back() {
+    let mut world = world_with_pickup();
+    assert_eq!(world.display_state().pickups.len(), 1);
+
+    step_seconds(&mut world, 0.1);
+    let display_state = world.display_state();
+    assert!(display_state.pickups.is_empty());
+    assert!(display_state.events.iter().any(
+        |(_, event)| *event == CollisionEvent::PickedUp(PlayerId(0), PickupKind::SpeedBoost, 0)
+    ));
+
+    // The player has fallen away from the pickup by the time it is back
+    step_seconds(&mut world, 1.0);
+    assert_eq!(world.display_state().pickups.len(), 1);
+}
+
+#[test]
+fn snapshot_restores_pickups() {
+    let mut server_world = world_with_pickup();
+    step_seconds(&mut server_world, 0.1);
+
+    let mut client_world = world_with_pickup();
+    client_world.apply_snapshot(server_world.snapshot());
+    assert!(client_world.display_state().pickups.is_empty());
+}
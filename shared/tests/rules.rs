@@ -0,0 +1,55 @@
+use crystalorb::{fixed_timestepper::Stepper, world::World};
+use orbgame_shared::{
+    arena::Arena,
+    game::{GameCommand, GameWorld},
+    match_state::{MatchPhase, MatchSettings},
+    rules::GameRules,
+};
+
+fn big_players() -> GameRules {
+    GameRules {
+        player_radius: 15.0,
+        ..GameRules::default()
+    }
+}
+
+#[test]
+fn match_starts_with_configured_rules() {
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::ConfigureMatch {
+        settings: MatchSettings::default(),
+        world: Default::default(),
+        rotation: vec![Arena::default()],
+        rules: big_players(),
+    });
+    assert_eq!(world.display_state().player_radius, 15.0);
+}
+
+#[test]
+fn updated_rules_take_effect_when_the_next_round_starts() {
+    let tick = orbgame_shared::TIMESTEP;
+    let mut world = GameWorld::new();
+    world.apply_command(&GameCommand::ConfigureMatch {
+        settings: MatchSettings {
+            warmup_seconds: 2.0 * tick,
+            ..MatchSettings::default()
+        },
+        world: Default::default(),
+        rotation: vec![Arena::default()],
+        rules: GameRules::default(),
+    });
+    world.apply_command(&GameCommand::UpdateRules(big_players()));
+    assert_eq!(world.display_state().player_radius, 10.0);
+    assert_eq!(world.display_state().rules, GameRules::default());
+
+    // Rollback keeps the pending rules
+    let mut client_world = GameWorld::new();
+    client_world.apply_snapshot(world.snapshot());
+    for world in [&mut world, &mut client_world].iter_mut() {
+        world.step();
+        world.step();
+        let display_state = world.display_state();
+        assert_eq!(display_state.phase, MatchPhase::Playing);
+        assert_eq!(display_state.player_radius, 15.0);
+    }
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use common::moving_world;
+use crystalorb::{
+    fixed_timestepper::Stepper,
+    timestamp::{Timestamp, Timestamped},
+    world::World,
+};
+use orbgame_shared::game::{
+    codec::{CodecError, ReceivedSnapshots, SentSnapshots, SnapshotMessage, SnapshotPrecision},
+    GameSnapshot, GameWorld,
+};
+
+/// Step the world, and take a snapshot with the step's timestamp.
+fn next_snapshot(world: &mut GameWorld, timestamp: &mut Timestamp) -> Timestamped<GameSnapshot> {
+    world.step();
+    timestamp.increment();
+    Timestamped::new(world.snapshot(), *timestamp)
+}
+
+fn positions(received: &mut ReceivedSnapshots, message: &SnapshotMessage) -> GameWorld {
+    let mut world = GameWorld::new();
+    world.apply_snapshot(received.decode(message).unwrap().inner().clone());
+    world
+}
+
+#[test]
+fn snapshots_are_delta_encoded_against_acknowledged_ones() {
+    let mut world = moving_world();
+    let mut timestamp = Timestamp::default();
+    let mut sent = SentSnapshots::new(SnapshotPrecision::default());
+    let mut received = ReceivedSnapshots::default();
+
+    let snapshot = next_snapshot(&mut world, &mut timestamp);
+    let first = sent.encode(1, &snapshot);
+    assert_eq!(first.baseline, None);
+    // Every client gets the same number for a snapshot
+    assert_eq!(sent.encode(2, &snapshot).id, first.id);
+    received.decode(&first).unwrap();
+    let acknowledgements = received.take_acknowledgements();
+    assert_eq!(acknowledgements, vec![first.id]);
+    assert!(received.take_acknowledgements().is_empty());
+    sent.acknowledge(1, &acknowledgements);
+
+    let snapshot = next_snapshot(&mut world, &mut timestamp);
+    // A client that has not acknowledged anything gets the snapshot without a baseline
+    let full = sent.encode(2, &snapshot);
+    let delta = sent.encode(1, &snapshot);
+    assert_eq!(full.baseline, None);
+    assert_eq!(delta.baseline, Some(first.id));
+    assert!(delta.bytes.len() < full.bytes.len());
+
+    let full_positions = positions(&mut ReceivedSnapshots::default(), &full)
+        .display_state()
+        .player_positions;
+    let delta_positions = positions(&mut received, &delta)
+        .display_state()
+        .player_positions;
+    assert_eq!(delta_positions, full_positions);
+}
+
+#[test]
+fn rejected_clients_do_not_hold_back_delta_encoding() {
+    let mut world = moving_world();
+    let mut timestamp = Timestamp::default();
+    let mut sent = SentSnapshots::new(SnapshotPrecision::default());
+    let mut received = ReceivedSnapshots::default();
+
+    // Client 2 never acknowledges anything, and is rejected after a while
+    for step in 0..10 {
+        let snapshot = next_snapshot(&mut world, &mut timestamp);
+        sent.encode(2, &snapshot);
+        received.decode(&sent.encode(1, &snapshot)).unwrap();
+        sent.acknowledge(1, &received.take_acknowledgements());
+        if step == 5 {
+            sent.forget_client(2);
+        }
+    }
+
+    let snapshot = next_snapshot(&mut world, &mut timestamp);
+    let message = sent.encode(1, &snapshot);
+    assert!(message.baseline.is_some());
+    assert!(received.decode(&message).is_ok());
+    // A client with the same handle starts over without a baseline
+    assert_eq!(sent.encode(2, &snapshot).baseline, None);
+}
+
+#[test]
+fn snapshots_against_unknown_baselines_are_not_decoded() {
+    let mut world = moving_world();
+    let mut timestamp = Timestamp::default();
+    let mut sent = SentSnapshots::new(SnapshotPrecision::default());
+    let mut received = ReceivedSnapshots::default();
+
+    let first = sent.encode(1, &next_snapshot(&mut world, &mut timestamp));
+    received.decode(&first).unwrap();
+    sent.acknowledge(1, &received.take_acknowledgements());
+    let delta = sent.encode(1, &next_snapshot(&mut world, &mut timestamp));
+
+    // A welcome from a server forgets the snapshots of the last one
+    received.reset();
+    assert!(matches!(
+        received.decode(&delta),
+        Err(CodecError::UnknownBaseline(id)) if id == first.id
+    ));
+}
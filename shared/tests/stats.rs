@@ -0,0 +1,56 @@
+use orbgame_shared::stats::{self, Channel, ChannelCounts, NetworkStats};
+
+#[test]
+fn messages_are_counted_per_connection_and_in_total() {
+    let mut network_stats = NetworkStats::default();
+    network_stats.record_sent(1, Channel::Command, 10);
+    network_stats.record_sent(2, Channel::Command, 20);
+    network_stats.record_received(2, Channel::Snapshot, 100);
+    network_stats.record_round_trip_time(2, 0.05);
+    // A closed connection's messages stay in the totals
+    network_stats.forget_connection(1);
+
+    assert!(network_stats.connection(1).is_none());
+    let connection = network_stats.connection(2).unwrap();
+    assert_eq!(
+        connection.commands,
+        ChannelCounts {
+            messages_sent: 1,
+            bytes_sent: 20,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        connection.snapshots,
+        ChannelCounts {
+            messages_received: 1,
+            bytes_received: 100,
+            ..Default::default()
+        }
+    );
+    assert_eq!(connection.round_trip_time, Some(0.05));
+    let total = network_stats.total();
+    assert_eq!(total.commands.messages_sent, 2);
+    assert_eq!(total.commands.bytes_sent, 30);
+    assert_eq!(total.channel(Channel::Snapshot), &connection.snapshots);
+}
+
+#[test]
+fn histogram_buckets_are_cumulative() {
+    let mut histogram = stats::Histogram::new(vec![0.001, 0.01]);
+    for value in &[0.0005, 0.001, 0.005, 0.5] {
+        histogram.observe(*value);
+    }
+    let mut text = String::new();
+    histogram.write_prometheus(&mut text, "tick_seconds", "Tick duration.");
+    assert_eq!(
+        text,
+        "# HELP tick_seconds Tick duration.\n\
+         # TYPE tick_seconds histogram\n\
+         tick_seconds_bucket{le=\"0.001\"} 2\n\
+         tick_seconds_bucket{le=\"0.01\"} 3\n\
+         tick_seconds_bucket{le=\"+Inf\"} 4\n\
+         tick_seconds_sum 0.5065\n\
+         tick_seconds_count 4\n"
+    );
+}